@@ -0,0 +1,187 @@
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+//root system description pointer, found by scanning the BIOS memory areas
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    //the fields below only exist for revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all ACPI system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A processor's local APIC as listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An I/O APIC as listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// The parts of the Multiple APIC Description Table the kernel cares about.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+}
+
+//MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+//all bytes of a valid ACPI structure sum up to zero
+fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+//looks for the "RSD PTR " signature on a 16 byte boundary in the given physical range
+fn scan_for_rsdp(start: u64, end: u64) -> Option<PhysAddr> {
+    (start..end).step_by(16).map(PhysAddr::new).find(|&addr| {
+        let signature = unsafe { ptr::read_unaligned(phys_to_virt(addr).as_ptr::<[u8; 8]>()) };
+        &signature == b"RSD PTR " && checksum_ok(addr, 20)
+    })
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    //the first KiB of the extended BIOS data area, whose segment is stored at 0x40E
+    let ebda_segment = unsafe { ptr::read_unaligned(phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>()) };
+    let ebda = u64::from(ebda_segment) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_for_rsdp(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    //the main BIOS area below 1 MiB
+    scan_for_rsdp(0xE0000, 0x100000)
+}
+
+/// Reads the header of the table at the given physical address.
+pub fn header(table: PhysAddr) -> SdtHeader {
+    unsafe { ptr::read_unaligned(phys_to_virt(table).as_ptr::<SdtHeader>()) }
+}
+
+/// Returns the physical address of the first table with the given signature, e.g. `b"APIC"` or `b"HPET"`.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = phys_to_virt(rsdp_addr).as_ptr::<Rsdp>();
+    let revision = unsafe { ptr::read_unaligned(ptr::addr_of!((*rsdp).revision)) };
+
+    //the XSDT holds 64-bit pointers, the older RSDT 32-bit ones
+    let (root, entry_size) = if revision >= 2 {
+        let xsdt = unsafe { ptr::read_unaligned(ptr::addr_of!((*rsdp).xsdt_address)) };
+        (PhysAddr::new(xsdt), 8)
+    } else {
+        let rsdt = unsafe { ptr::read_unaligned(ptr::addr_of!((*rsdp).rsdt_address)) };
+        (PhysAddr::new(u64::from(rsdt)), 4)
+    };
+
+    //don't trust the length of a broken table, it decides how far we read
+    let root_length = header(root).length as usize;
+    if root_length < mem::size_of::<SdtHeader>() || !checksum_ok(root, root_length) {
+        return None;
+    }
+    let entries = (root_length - mem::size_of::<SdtHeader>()) / entry_size;
+    let first_entry = root + mem::size_of::<SdtHeader>();
+
+    (0..entries)
+        .map(|i| {
+            let entry = phys_to_virt(first_entry + i * entry_size);
+            let table = unsafe {
+                if entry_size == 8 {
+                    ptr::read_unaligned(entry.as_ptr::<u64>())
+                } else {
+                    u64::from(ptr::read_unaligned(entry.as_ptr::<u32>()))
+                }
+            };
+            PhysAddr::new(table)
+        })
+        .find(|&table| {
+            let table_header = header(table);
+            &table_header.signature == signature
+                && table_header.length as usize >= mem::size_of::<SdtHeader>()
+                && checksum_ok(table, table_header.length as usize)
+        })
+}
+
+/// Parses the MADT, which lists the processors and interrupt controllers of the machine.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let length = header(table).length as u64;
+    let base = phys_to_virt(table);
+
+    //the header is followed by the local APIC address and a flags field
+    let local_apic_address = unsafe {
+        ptr::read_unaligned((base + mem::size_of::<SdtHeader>()).as_ptr::<u32>())
+    };
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+    };
+
+    //variable length entries of the form (type, length, data...)
+    let mut offset = mem::size_of::<SdtHeader>() as u64 + 8;
+    while offset + 2 <= length {
+        let entry = (base + offset).as_ptr::<u8>();
+        let (kind, len) = unsafe { (*entry, *entry.add(1)) };
+        if len < 2 {
+            break; //malformed table, bail out instead of looping forever
+        }
+        unsafe {
+            match kind {
+                MADT_LOCAL_APIC => madt.local_apics.push(LocalApic {
+                    processor_id: *entry.add(2),
+                    apic_id: *entry.add(3),
+                    enabled: ptr::read_unaligned(entry.add(4) as *const u32) & 1 != 0,
+                }),
+                MADT_IO_APIC => madt.io_apics.push(IoApic {
+                    id: *entry.add(2),
+                    address: PhysAddr::new(u64::from(ptr::read_unaligned(entry.add(4) as *const u32))),
+                    gsi_base: ptr::read_unaligned(entry.add(8) as *const u32),
+                }),
+                MADT_LOCAL_APIC_OVERRIDE => {
+                    madt.local_apic_address = PhysAddr::new(ptr::read_unaligned(entry.add(4) as *const u64));
+                }
+                _ => {}
+            }
+        }
+        offset += u64::from(len);
+    }
+
+    Some(madt)
+}
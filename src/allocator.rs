@@ -13,6 +13,7 @@ use linked_list_allocator::LockedHeap;
 use bump::BumpAllocator;
 use linked_list::LinkedListAllocator;
 use fixed_size_block::FixedSizeBlockAllocator;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
 
 pub struct Dummy;
 pub const HEAP_START: usize = 0x_4444_4444_0000; //memory starting address
@@ -68,19 +69,20 @@ pub fn init_heap(
 
 
 
-//A wrapper around spin::Mutex to permit trait implementations
+//A wrapper around IrqSafeMutex to permit trait implementations
+//interrupts stay off while the lock is held, so a thread is never preempted in the middle of an allocation
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,90 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB},
+    PhysAddr,
+};
+use crate::memory;
+
+//virtual address of the local APIC registers, the same physical page on every CPU
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+//register offsets
+const ID: u64 = 0x20;
+const EOI: u64 = 0xB0;
+const SPURIOUS: u64 = 0xF0;
+const ERROR_STATUS: u64 = 0x280;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+
+//interrupt command register bits
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Vector the local APIC raises for spurious interrupts. They must not get an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Maps the local APIC registers at the address from the MADT and enables the APIC of the calling CPU.
+pub fn init(
+    base: PhysAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let virt = memory::map_mmio(base, 4096, mapper, frame_allocator)?;
+    LAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+    enable();
+    Ok(())
+}
+
+/// Whether `init` has mapped the registers yet.
+pub fn is_initialized() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+fn read(register: u64) -> u32 {
+    let addr = LAPIC_BASE.load(Ordering::Relaxed) + register;
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+fn write(register: u64, value: u32) {
+    let addr = LAPIC_BASE.load(Ordering::Relaxed) + register;
+    unsafe { (addr as *mut u32).write_volatile(value) };
+}
+
+/// Enables the local APIC of the calling CPU. Every CPU has to do this for itself.
+pub fn enable() {
+    write(SPURIOUS, read(SPURIOUS) | APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+/// APIC id of the calling CPU.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Signals the end of an interrupt that was delivered through the local APIC.
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+//writes the interrupt command register and waits until the APIC accepted the IPI
+fn send_ipi(apic_id: u8, command: u32) {
+    write(ERROR_STATUS, 0);
+    write(ICR_HIGH, u32::from(apic_id) << 24);
+    write(ICR_LOW, command); //writing the low half sends the IPI
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends an INIT IPI, which resets the target processor into its wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI; the target starts executing in real mode at `page * 0x1000`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
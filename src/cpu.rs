@@ -0,0 +1,152 @@
+use core::arch::x86_64::{CpuidResult, __cpuid};
+use core::fmt;
+use core::str;
+use lazy_static::lazy_static;
+
+//leaves we query
+const VENDOR_LEAF: u32 = 0x0;
+const FEATURE_LEAF: u32 = 0x1;
+const EXTENDED_MAX_LEAF: u32 = 0x8000_0000;
+const EXTENDED_FEATURE_LEAF: u32 = 0x8000_0001;
+const POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+
+/// CPU features the kernel may want to rely on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub tsc: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    pub pae: bool,
+    pub nx: bool,
+    pub huge_pages_1gib: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub xsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    pub tsc_deadline: bool,
+    pub invariant_tsc: bool,
+}
+
+/// What CPUID reports about the processor.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+}
+
+lazy_static! { //CPUID is slow (it serializes the pipeline and traps under virtualization), so only query it once
+    static ref INFO: CpuInfo = CpuInfo::detect();
+}
+
+/// Returns the information gathered from CPUID on first use.
+pub fn info() -> &'static CpuInfo {
+    &INFO
+}
+
+/// Shorthand for `info().features`.
+pub fn features() -> &'static Features {
+    &INFO.features
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) } //every x86_64 processor supports the cpuid instruction
+}
+
+fn bit(register: u32, bit: u32) -> bool {
+    register & (1 << bit) != 0
+}
+
+impl CpuInfo {
+    fn detect() -> Self {
+        let vendor_leaf = cpuid(VENDOR_LEAF);
+        let mut vendor = [0; 12];
+        //the vendor string is spread over ebx, edx, ecx in that order
+        vendor[0..4].copy_from_slice(&vendor_leaf.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&vendor_leaf.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&vendor_leaf.ecx.to_le_bytes());
+
+        let leaf1 = cpuid(FEATURE_LEAF);
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((leaf1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model | (((leaf1.eax >> 16) & 0xF) << 4)
+        } else {
+            base_model
+        };
+
+        let mut features = Features {
+            tsc: bit(leaf1.edx, 4),
+            pae: bit(leaf1.edx, 6),
+            apic: bit(leaf1.edx, 9),
+            fxsr: bit(leaf1.edx, 24),
+            sse: bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+            x2apic: bit(leaf1.ecx, 21),
+            tsc_deadline: bit(leaf1.ecx, 24),
+            xsave: bit(leaf1.ecx, 26),
+            avx: bit(leaf1.ecx, 28),
+            rdrand: bit(leaf1.ecx, 30),
+            ..Features::default()
+        };
+
+        //extended leaves only exist up to the maximum reported by 0x80000000
+        let max_extended = cpuid(EXTENDED_MAX_LEAF).eax;
+        if max_extended >= EXTENDED_FEATURE_LEAF {
+            let extended = cpuid(EXTENDED_FEATURE_LEAF);
+            features.nx = bit(extended.edx, 20);
+            features.huge_pages_1gib = bit(extended.edx, 26);
+        }
+        if max_extended >= POWER_MANAGEMENT_LEAF {
+            features.invariant_tsc = bit(cpuid(POWER_MANAGEMENT_LEAF).edx, 8);
+        }
+
+        CpuInfo {
+            vendor,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            features,
+        }
+    }
+
+    /// The vendor string, e.g. "GenuineIntel" or "AuthenticAMD".
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+}
+
+//one line summary for the boot banner
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CPU: {} family {:#x} model {:#x} stepping {}",
+            self.vendor(), self.family, self.model, self.stepping)?;
+
+        let features = &self.features;
+        let flags = [
+            ("apic", features.apic),
+            ("x2apic", features.x2apic),
+            ("nx", features.nx),
+            ("pae", features.pae),
+            ("1g-pages", features.huge_pages_1gib),
+            ("rdrand", features.rdrand),
+            ("tsc-deadline", features.tsc_deadline),
+            ("xsave", features.xsave),
+            ("invariant-tsc", features.invariant_tsc),
+        ];
+        write!(f, "\nflags:")?;
+        for (name, _) in flags.iter().filter(|(_, present)| *present) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+use crate::memory::{self, AddressSpace};
+use crate::usermode::{USER_REGION_END, USER_REGION_START};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const CURRENT_VERSION: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+//auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Where the user stack ends (it grows down from here) and how large it is.
+pub const USER_STACK_TOP: u64 = USER_REGION_END - 4096; //leave a guard page below the end of the region
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Not64Bit,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeader,
+    SegmentOutsideUserRegion,
+    EntryNotExecutable, //the entry point lies outside every executable PT_LOAD segment
+    StackTooSmall,
+    NotMapped, //a write into the new address space hit an unmapped page
+    OutOfMemory,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+/// The fields of the ELF file header the loader needs.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub program_header_count: u16,
+}
+
+/// One entry of the program header table.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+/// A program ready to be started with `usermode::enter_user_mode`.
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    pub address_space: AddressSpace,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Validates the file header: a little endian x86_64 ELF64 executable.
+pub fn parse_header(data: &[u8]) -> Result<ElfHeader, ElfError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ElfError::TooShort);
+    }
+    if data[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if data[4] != CLASS_64 {
+        return Err(ElfError::Not64Bit);
+    }
+    if data[5] != LITTLE_ENDIAN {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if data[6] != CURRENT_VERSION {
+        return Err(ElfError::BadVersion);
+    }
+    if u16_at(data, 16) != TYPE_EXECUTABLE {
+        return Err(ElfError::NotExecutable); //no relocation support, so no position independent executables
+    }
+    if u16_at(data, 18) != MACHINE_X86_64 {
+        return Err(ElfError::WrongMachine);
+    }
+    if usize::from(u16_at(data, 54)) != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeader);
+    }
+
+    let header = ElfHeader {
+        entry: u64_at(data, 24),
+        program_header_offset: u64_at(data, 32),
+        program_header_count: u16_at(data, 56),
+    };
+    let table_end = header.program_header_offset
+        .checked_add(u64::from(header.program_header_count) * PROGRAM_HEADER_SIZE as u64)
+        .ok_or(ElfError::BadProgramHeader)?;
+    if table_end > data.len() as u64 {
+        return Err(ElfError::TooShort);
+    }
+    Ok(header)
+}
+
+/// Reads the program header table; `parse_header` must have accepted `data`.
+pub fn program_headers(data: &[u8], header: &ElfHeader) -> Vec<ProgramHeader> {
+    (0..usize::from(header.program_header_count))
+        .map(|i| {
+            let base = header.program_header_offset as usize + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: u32_at(data, base),
+                flags: u32_at(data, base + 4),
+                offset: u64_at(data, base + 8),
+                virtual_address: u64_at(data, base + 16),
+                file_size: u64_at(data, base + 32),
+                memory_size: u64_at(data, base + 40),
+            }
+        })
+        .collect()
+}
+
+//a loadable segment must lie completely in the user region and in the file
+fn check_segment(data: &[u8], segment: &ProgramHeader) -> Result<(), ElfError> {
+    let file_end = segment.offset.checked_add(segment.file_size).ok_or(ElfError::BadProgramHeader)?;
+    if file_end > data.len() as u64 || segment.file_size > segment.memory_size {
+        return Err(ElfError::BadProgramHeader);
+    }
+    let end = segment.virtual_address
+        .checked_add(segment.memory_size)
+        .ok_or(ElfError::SegmentOutsideUserRegion)?;
+    if segment.virtual_address < USER_REGION_START || end > USER_STACK_TOP - USER_STACK_SIZE {
+        return Err(ElfError::SegmentOutsideUserRegion);
+    }
+    Ok(())
+}
+
+fn segment_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+//maps a zeroed frame for every page of the range; pages shared by two segments get the union of their permissions
+fn map_range(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    start: u64,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ElfError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(start + size - 1));
+
+    for page in Page::range_inclusive(first, last) {
+        if let TranslateResult::Mapped { flags: existing, .. } = mapper.translate(page.start_address()) {
+            let mut merged = existing | flags;
+            if !existing.contains(PageTableFlags::NO_EXECUTE) || !flags.contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            //the address space is not active, so there is nothing to flush
+            unsafe { mapper.update_flags(page, merged).map_err(|_| ElfError::BadProgramHeader)?.ignore() };
+            continue;
+        }
+
+        let frame = frame_allocator.allocate_frame().ok_or(ElfError::OutOfMemory)?;
+        unsafe {
+            memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096); //this also zeroes the BSS
+            mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)?.ignore();
+        }
+    }
+    Ok(())
+}
+
+//writes into an address space that does not have to be active, going through the physical memory mapping
+fn copy_to_user(mapper: &impl Translate, mut address: u64, mut bytes: &[u8]) -> Result<(), ElfError> {
+    while !bytes.is_empty() {
+        let phys = mapper
+            .translate_addr(VirtAddr::new(address))
+            .ok_or(ElfError::NotMapped)?;
+        let in_page = (4096 - address % 4096) as usize;
+        let chunk = in_page.min(bytes.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), memory::phys_to_virt(phys).as_mut_ptr(), chunk);
+        }
+        address += chunk as u64;
+        bytes = &bytes[chunk..];
+    }
+    Ok(())
+}
+
+//lays out the initial stack as the System V ABI describes it, from the top down:
+//strings, padding, auxv, envp, argv, argc (where the stack pointer points)
+fn build_stack(
+    mapper: &impl Translate,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    //check that everything fits before writing any of it
+    let strings_size: u64 = args.iter().chain(env).map(|s| s.len() as u64 + 1).sum();
+    let word_count = (args.len() + env.len() + 2 * auxv.len() + 5) as u64; //argc, two nulls, AT_NULL pair
+    let stack_pointer = strings_size
+        .checked_add(word_count * 8)
+        .filter(|&size| size <= USER_STACK_SIZE)
+        .map(|size| (USER_STACK_TOP - size) & !0xF) //argc has to sit at a 16 byte aligned address
+        .filter(|&stack_pointer| USER_STACK_TOP - stack_pointer <= USER_STACK_SIZE)
+        .ok_or(ElfError::StackTooSmall)?;
+
+    let mut top = USER_STACK_TOP;
+    let mut push_strings = |strings: &[&str]| -> Result<Vec<u64>, ElfError> {
+        strings
+            .iter()
+            .map(|s| {
+                top -= s.len() as u64 + 1;
+                copy_to_user(mapper, top, s.as_bytes())?;
+                copy_to_user(mapper, top + s.len() as u64, &[0])?;
+                Ok(top)
+            })
+            .collect()
+    };
+    let arg_pointers = push_strings(args)?;
+    let env_pointers = push_strings(env)?;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(args.len() as u64);
+    words.extend(&arg_pointers);
+    words.push(0);
+    words.extend(&env_pointers);
+    words.push(0);
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    copy_to_user(mapper, stack_pointer, &bytes)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Loads an executable into a new address space and prepares its stack.
+///
+/// Every `PT_LOAD` segment is mapped with the permissions from its flags, the part past the
+/// file contents is zeroed, and a stack with `args`, `env` and an auxiliary vector is set up.
+/// On failure, every frame taken for the new address space is given back.
+pub fn load<A>(
+    data: &[u8],
+    args: &[&str],
+    env: &[&str],
+    frame_allocator: &mut A,
+) -> Result<LoadedProgram, ElfError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let header = parse_header(data)?;
+    let segments = program_headers(data, &header);
+    let entry_is_code = segments
+        .iter()
+        .filter(|s| s.kind == PT_LOAD && s.flags & PF_X != 0)
+        .any(|s| s.virtual_address <= header.entry
+            && s.virtual_address.checked_add(s.memory_size).map_or(false, |end| header.entry < end));
+    if !entry_is_code {
+        return Err(ElfError::EntryNotExecutable);
+    }
+
+    let mut address_space = AddressSpace::new(frame_allocator).ok_or(ElfError::OutOfMemory)?;
+    match fill_address_space(&mut address_space, data, &header, &segments, args, env, frame_allocator) {
+        Ok(stack_pointer) => Ok(LoadedProgram {
+            entry: VirtAddr::new(header.entry),
+            stack_pointer,
+            address_space,
+        }),
+        Err(err) => {
+            address_space.free(frame_allocator);
+            Err(err)
+        }
+    }
+}
+
+//maps the segments and the stack of the program, returns the initial stack pointer
+fn fill_address_space(
+    address_space: &mut AddressSpace,
+    data: &[u8],
+    header: &ElfHeader,
+    segments: &[ProgramHeader],
+    args: &[&str],
+    env: &[&str],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, ElfError> {
+    let mut mapper = address_space.mapper();
+
+    for segment in segments.iter().filter(|s| s.kind == PT_LOAD) {
+        check_segment(data, segment)?;
+        map_range(&mut mapper, segment.virtual_address, segment.memory_size, segment_flags(segment), frame_allocator)?;
+        let contents = &data[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+        copy_to_user(&mapper, segment.virtual_address, contents)?;
+    }
+
+    let stack_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_range(&mut mapper, USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE, stack_flags, frame_allocator)?;
+
+    //where the program headers ended up in memory: given directly by PT_PHDR, or inside the segment loaded from that file offset
+    let phdr_address = segments
+        .iter()
+        .find(|s| s.kind == PT_PHDR)
+        .map(|s| s.virtual_address)
+        .or_else(|| {
+            segments
+                .iter()
+                .filter(|s| s.kind == PT_LOAD)
+                .find(|s| s.offset <= header.program_header_offset
+                    && header.program_header_offset < s.offset + s.file_size)
+                .map(|s| s.virtual_address + header.program_header_offset - s.offset)
+        });
+
+    let mut auxv = Vec::new();
+    if let Some(address) = phdr_address {
+        auxv.push((AT_PHDR, address));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, u64::from(header.program_header_count)));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, header.entry));
+
+    build_stack(&mapper, args, env, &auxv)
+}
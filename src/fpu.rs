@@ -0,0 +1,200 @@
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use crate::{cpu, per_cpu, percpu};
+
+/// Size reserved for a saved FPU/SSE/AVX context. XSAVE areas with AVX-512 need about 2.7 KiB.
+pub const STATE_AREA_SIZE: usize = 4096;
+
+//default control words of a freshly initialized FPU
+const DEFAULT_FCW: u16 = 0x037F; //all exceptions masked, extended precision
+const DEFAULT_MXCSR: u32 = 0x1F80; //all SSE exceptions masked, round to nearest
+const MXCSR_OFFSET: usize = 24;
+
+//loaded for contexts without a save area, so they never see registers of the previous owner
+static INITIAL_STATE: FpuState = FpuState::initial();
+
+per_cpu! {
+    //context whose state currently lives in this CPU's FPU registers
+    static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+}
+per_cpu! {
+    //context the CPU is currently executing, its state is loaded on first FPU use
+    static CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// Saved FPU, SSE and (with XSAVE) AVX registers of one execution context.
+#[repr(C, align(64))]
+pub struct FpuState {
+    area: [u8; STATE_AREA_SIZE],
+}
+
+/// Enables the FPU and SSE (and XSAVE/AVX if the CPU supports them) on the calling CPU.
+///
+/// The kernel itself is built without SSE, so this only matters for code that does
+/// use the registers, like user programs or threads that opt in through `FpuState`.
+pub fn init() {
+    let features = cpu::features();
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if features.xsave {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+        if features.xsave {
+            let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.avx {
+                components |= XCr0Flags::AVX;
+            }
+            XCr0::write(components);
+        }
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+impl FpuState {
+    /// A state equivalent to a freshly initialized FPU.
+    pub fn new() -> Self {
+        FpuState::initial()
+    }
+
+    const fn initial() -> Self {
+        let mut area = [0; STATE_AREA_SIZE];
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        area[0] = fcw[0];
+        area[1] = fcw[1];
+        let mut i = 0;
+        while i < mxcsr.len() {
+            area[MXCSR_OFFSET + i] = mxcsr[i];
+            i += 1;
+        }
+        //an all zero XSAVE header marks every other component as being in its initial state
+        FpuState { area }
+    }
+
+    /// Stores the current register contents into this area (eager save).
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if cpu::features().xsave {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+    }
+
+    /// Loads the registers from this area (eager restore).
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if cpu::features().xsave {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        //make sure no CPU's #NM handler ever saves into freed memory; a thread may have
+        //migrated, so its state can still be owned by a CPU other than this one
+        let this = self as *mut FpuState;
+        for cpu_id in 0..percpu::MAX_CPUS {
+            let _ = OWNER.for_cpu(cpu_id).compare_exchange(this, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+            let _ = CURRENT.for_cpu(cpu_id).compare_exchange(this, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Lazily switches the FPU to `state`, called by whatever switches execution contexts.
+///
+/// Nothing is saved or restored here: if `state` does not already own the registers, CR0.TS
+/// is set and the first FPU instruction traps into `handle_device_not_available`, which does
+/// the actual swap. Contexts that never touch the FPU therefore never pay for it.
+///
+/// The caller must keep `state` alive (and at the same address) until it switches away again.
+pub fn switch_to(state: *mut FpuState) {
+    CURRENT.get().store(state, Ordering::Relaxed);
+    unsafe {
+        if OWNER.get().load(Ordering::Relaxed) == state {
+            asm!("clts", options(nomem, nostack));
+        } else {
+            Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+        }
+    }
+}
+
+//called from the #NM exception: hand the registers from their previous owner to the running context
+pub(crate) fn handle_device_not_available() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+
+    let owner = OWNER.get().load(Ordering::Relaxed);
+    let current = CURRENT.get().load(Ordering::Relaxed);
+    if owner == current {
+        return;
+    }
+    unsafe {
+        if let Some(owner) = owner.as_mut() {
+            owner.save();
+        }
+        match current.as_ref() {
+            Some(current) => current.restore(),
+            None => INITIAL_STATE.restore(), //kernel context without a save area
+        }
+    }
+    OWNER.get().store(current, Ordering::Relaxed);
+}
+
+
+//reads MXCSR, traps into the #NM handler if the registers belong to another context
+fn mxcsr() -> u32 {
+    let mut value = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+    value
+}
+
+fn set_mxcsr(value: u32) {
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly)) };
+}
+
+#[test_case]
+fn test_lazy_switch_keeps_contexts_apart() {
+    use alloc::boxed::Box;
+
+    const ROUND_TOWARD_ZERO: u32 = 0x6000;
+    let mut first = Box::new(FpuState::new());
+    let mut second = Box::new(FpuState::new());
+
+    switch_to(&mut *first);
+    set_mxcsr(DEFAULT_MXCSR | ROUND_TOWARD_ZERO);
+    switch_to(&mut *second);
+    assert_eq!(mxcsr(), DEFAULT_MXCSR);
+    switch_to(&mut *first);
+    assert_eq!(mxcsr(), DEFAULT_MXCSR | ROUND_TOWARD_ZERO);
+    switch_to(ptr::null_mut());
+    assert_eq!(mxcsr(), DEFAULT_MXCSR, "kernel context saw another context's registers");
+
+    //as if `second` was last used on another CPU, which the lib tests never start
+    let second_ptr: *mut FpuState = &mut *second;
+    OWNER.for_cpu(1).store(second_ptr, Ordering::Relaxed);
+    drop(first);
+    drop(second);
+    assert!(OWNER.for_cpu(1).load(Ordering::Relaxed).is_null());
+}
@@ -3,45 +3,109 @@ use x86_64::structures::tss::TaskStateSegment; //using TaskStateSegment(TSS)
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor}; //using GDT(Global Descriptor Table)
 use x86_64::structures::gdt::SegmentSelector;
 use lazy_static::lazy_static;
+use alloc::boxed::Box;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; //define 0th IST entry is double fault stack
+pub const NMI_IST_INDEX: u16 = 1; //non-maskable interrupts can arrive at any point, even mid stack switch
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+pub const IST_STACK_COUNT: usize = 4; //number of IST entries above that are in use
 
-lazy_static! {  //use lazy static becoz Rust’s const evaluator is not yet powerful enough to do this initialization at compile time.
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new(); //create new TSS
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; //used as stack storage
+pub const IST_STACK_SIZE: usize = 4096 * 5; //size of each interrupt stack
+pub const PRIVILEGE_STACK_SIZE: usize = 4096 * 5; //kernel stack the CPU switches to when entering ring 0 from ring 3
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+//reserves a separate static stack of `$size` bytes and evaluates to its top, since stacks grow downwards
+macro_rules! static_stack {
+    ($size:expr) => {{
+        static mut STACK: [u8; $size] = [0; $size]; //used as stack storage
 
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + $size;
+        stack_end
+    }};
+}
 
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment()); //since GDT is changed, reload the code segment
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS)); //access to TSS selector so that CPU can use it
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+lazy_static! {  //use lazy static becoz Rust’s const evaluator is not yet powerful enough to do this initialization at compile time.
+    //the bootstrap processor's GDT, first used by `init`; its TSS lives in the per-CPU block,
+    //application processors get their own through `init_ap`
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(crate::percpu::install_tss(new_tss(
+        [
+            static_stack!(IST_STACK_SIZE),
+            static_stack!(IST_STACK_SIZE),
+            static_stack!(IST_STACK_SIZE),
+            static_stack!(IST_STACK_SIZE),
+        ],
+        static_stack!(PRIVILEGE_STACK_SIZE),
+    )));
 }
 
 
-struct Selectors {
-    code_selector: SegmentSelector,
+/// Segment selectors of the GDT. The layout is the same on every CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() { //initialize GDT
+//creates a TSS whose IST entries point at the given stack tops, in IST index order
+fn new_tss(ist_stacks: [VirtAddr; IST_STACK_COUNT], privilege_stack: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new(); //create new TSS
+    for (index, stack_end) in ist_stacks.iter().enumerate() {
+        tss.interrupt_stack_table[index] = *stack_end;
+    }
+    tss.privilege_stack_table[0] = privilege_stack; //used on every interrupt or exception that arrives in ring 3
+    tss
+}
+
+//every CPU needs its own TSS (a TSS is marked busy when loaded), hence also its own GDT
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment()); //since GDT is changed, reload the code segment
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    //sysret expects the user data segment right before the user code segment, see syscall STAR
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss)); //access to TSS selector so that CPU can use it
+    (gdt, Selectors { code_selector, data_selector, user_data_selector, user_code_selector, tss_selector })
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss; //load TSS
-    use x86_64::instructions::segmentation::{CS, Segment}; //reload code segment
-    
-    GDT.0.load();
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment}; //reload code and data segments
+
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector); //the bootloader's selector would point at a different entry now
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+pub fn init() { //initialize GDT
+    load(&GDT);
+}
+
+/// Loads a new GDT and TSS on an application processor, using the given IST and ring 0 stacks.
+///
+/// The GDT is leaked since the CPU keeps using it until it is reset; the TSS goes to the CPU's
+/// per-CPU block, so `percpu::init` has to run first.
+pub fn init_ap(ist_stacks: [VirtAddr; IST_STACK_COUNT], privilege_stack: VirtAddr) {
+    let tss = crate::percpu::install_tss(new_tss(ist_stacks, privilege_stack));
+    load(Box::leak(Box::new(new_gdt(tss))));
+}
+
+//returns the top of the calling CPU's interrupt stack with the given IST index
+pub fn ist_stack_top(index: u16) -> VirtAddr {
+    crate::percpu::tss().interrupt_stack_table[index as usize]
+}
+
+
+//the selectors, which are identical in the GDT of every CPU
+pub fn selectors() -> Selectors {
+    GDT.1
+}
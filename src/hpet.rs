@@ -0,0 +1,277 @@
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB},
+    PhysAddr,
+};
+use crate::acpi::{self, SdtHeader};
+use crate::interrupts::irq::{self, IrqReturn};
+use crate::memory;
+use crate::time::{self, ClockSource, Instant};
+
+//general registers
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+//per timer registers, timer n lives at offset 0x20 * n
+const TIMER_CONFIGURATION: u64 = 0x100;
+const TIMER_COMPARATOR: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+
+const COUNTER_64BIT: u64 = 1 << 13; //capabilities: otherwise the main counter is only 32 bits wide
+const LEGACY_ROUTING_CAPABLE: u64 = 1 << 15; //capabilities
+
+const ENABLE: u64 = 1 << 0; //configuration: main counter runs
+const LEGACY_ROUTING: u64 = 1 << 1; //configuration: timer 0 replaces the PIT (IRQ0), timer 1 the RTC (IRQ8)
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5; //otherwise the comparator only holds 32 bits
+const TIMER_VALUE_SET: u64 = 1 << 6; //lets a write set the accumulator of a periodic timer
+
+const PERIODIC_TIMER: u64 = 0; //drives the system tick in legacy mode
+const ONE_SHOT_TIMER: u64 = 1;
+const ONE_SHOT_IRQ: u8 = 8; //where timer 1 is routed in legacy mode
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+const CALIBRATION_FEMTOS: u64 = 10 * 1_000_000_000_000; //10 ms
+
+static BASE: AtomicU64 = AtomicU64::new(0); //virtual address of the registers, 0 if there is no HPET
+static PERIOD: AtomicU64 = AtomicU64::new(0); //length of one counter tick in femtoseconds
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(u64::MAX); //bits the main counter has
+static ONE_SHOT_MASK: AtomicU64 = AtomicU64::new(u64::MAX); //bits the one-shot comparator matches on
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0); //last value of `extended_counter`
+static ONE_SHOT_ENABLED: AtomicBool = AtomicBool::new(false);
+static ARMED_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX); //nanos since boot the one-shot fires at
+
+//layout of the ACPI HPET table body, directly after the common header
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    hardware_revision: u8,
+    comparator_info: u8,
+    pci_vendor: u16,
+    address_space: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    reserved: u8,
+    address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug)]
+pub enum HpetError {
+    NotPresent,
+    MapFailed(MapToError<Size4KiB>),
+    NoLegacyRouting, //needed to route timers 0 and 1 to IRQ0 and IRQ8
+    NoPeriodicTimer, //timer 0 cannot replace the PIT tick
+}
+
+impl From<MapToError<Size4KiB>> for HpetError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        HpetError::MapFailed(err)
+    }
+}
+
+fn read(register: u64) -> u64 {
+    let addr = BASE.load(Ordering::Relaxed) + register;
+    unsafe { (addr as *const u64).read_volatile() }
+}
+
+fn write(register: u64, value: u64) {
+    let addr = BASE.load(Ordering::Relaxed) + register;
+    unsafe { (addr as *mut u64).write_volatile(value) };
+}
+
+/// Locates the HPET through ACPI, maps its registers and starts the main counter.
+///
+/// Also recalibrates the TSC against it, which is more precise than the PIT.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HpetError> {
+    let table = acpi::find_table(b"HPET").ok_or(HpetError::NotPresent)?;
+    let table_ptr = memory::phys_to_virt(table).as_ptr::<HpetTable>();
+    let address = unsafe { ptr::read_unaligned(ptr::addr_of!((*table_ptr).address)) };
+
+    let registers = memory::map_mmio(PhysAddr::new(address), 1024, mapper, frame_allocator)?;
+    BASE.store(registers.as_u64(), Ordering::Relaxed);
+    let capabilities = read(CAPABILITIES);
+    PERIOD.store(capabilities >> 32, Ordering::Relaxed);
+    if capabilities & COUNTER_64BIT == 0 {
+        COUNTER_MASK.store(u64::from(u32::MAX), Ordering::Relaxed);
+    }
+
+    write(CONFIGURATION, read(CONFIGURATION) | ENABLE);
+
+    let frequency = x86_64::instructions::interrupts::without_interrupts(calibrate_tsc);
+    time::set_tsc_frequency(frequency);
+    Ok(())
+}
+
+/// Whether `init` found and started an HPET.
+pub fn is_present() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Raw value of the main counter. Wraps at 2^32 on HPETs with a 32-bit counter.
+pub fn counter() -> u64 {
+    read(MAIN_COUNTER) & COUNTER_MASK.load(Ordering::Relaxed)
+}
+
+//ticks from `earlier` to `later`, across a wrap of the counter
+fn ticks_between(earlier: u64, later: u64) -> u64 {
+    later.wrapping_sub(earlier) & COUNTER_MASK.load(Ordering::Relaxed)
+}
+
+//the main counter widened to 64 bits; a 32-bit counter wraps every few minutes, which is
+//only noticed as long as this is called at least once per wrap
+fn extended_counter() -> u64 {
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    let raw = counter();
+    if mask == u64::MAX {
+        return raw;
+    }
+    let mut last = EXTENDED_COUNTER.load(Ordering::Relaxed);
+    loop {
+        let mut value = (last & !mask) | raw;
+        if value < last {
+            if last - value < mask / 2 {
+                return last; //read before another CPU stored a newer value, not a wrap
+            }
+            value += mask + 1;
+        }
+        match EXTENDED_COUNTER.compare_exchange_weak(last, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return value,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Counter frequency in Hz.
+pub fn frequency() -> u64 {
+    FEMTOS_PER_SEC / PERIOD.load(Ordering::Relaxed)
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(PERIOD.load(Ordering::Relaxed)) / u128::from(FEMTOS_PER_NANO)) as u64
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    (u128::from(nanos) * u128::from(FEMTOS_PER_NANO) / u128::from(PERIOD.load(Ordering::Relaxed))) as u64
+}
+
+fn calibrate_tsc() -> u64 {
+    let ticks = CALIBRATION_FEMTOS / PERIOD.load(Ordering::Relaxed);
+    let start_counter = counter();
+    let start_tsc = time::rdtsc();
+    while ticks_between(start_counter, counter()) < ticks {
+        core::hint::spin_loop();
+    }
+    let end_tsc = time::rdtsc();
+    (end_tsc - start_tsc) * (FEMTOS_PER_SEC / CALIBRATION_FEMTOS)
+}
+
+/// The HPET main counter as a clock source.
+pub struct HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn nanos(&self) -> u64 {
+        ticks_to_nanos(extended_counter())
+    }
+}
+
+/// Switches the HPET into legacy replacement mode to get one-shot interrupts for the timer subsystem.
+///
+/// In this mode timer 0 takes over IRQ0 from the PIT, so it is set up to tick at `tick_hz` to keep
+/// the system tick going, and timer 1 takes over IRQ8 from the RTC (so the RTC periodic interrupt
+/// can no longer be used) and is armed on demand by `schedule_wakeup`.
+pub fn enable_one_shot_interrupts(tick_hz: u64) -> Result<(), HpetError> {
+    if !is_present() {
+        return Err(HpetError::NotPresent);
+    }
+    let periodic = TIMER_CONFIGURATION + PERIODIC_TIMER * TIMER_STRIDE;
+    let one_shot = TIMER_CONFIGURATION + ONE_SHOT_TIMER * TIMER_STRIDE;
+    if read(CAPABILITIES) & LEGACY_ROUTING_CAPABLE == 0 {
+        return Err(HpetError::NoLegacyRouting);
+    }
+    if read(periodic) & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NoPeriodicTimer);
+    }
+    if read(one_shot) & TIMER_64BIT_CAPABLE == 0 {
+        ONE_SHOT_MASK.store(u64::from(u32::MAX), Ordering::Relaxed);
+    }
+    ONE_SHOT_MASK.fetch_and(COUNTER_MASK.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    irq::register(ONE_SHOT_IRQ, one_shot_handler).expect("failed to register HPET handler");
+
+    let tick_period = frequency() / tick_hz;
+    let first_tick = counter().wrapping_add(tick_period) & COUNTER_MASK.load(Ordering::Relaxed);
+
+    write(CONFIGURATION, read(CONFIGURATION) & !ENABLE); //stop the counter while reprogramming
+    write(periodic, read(periodic) | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+    write(TIMER_COMPARATOR + PERIODIC_TIMER * TIMER_STRIDE, first_tick);
+    write(TIMER_COMPARATOR + PERIODIC_TIMER * TIMER_STRIDE, tick_period); //second write sets the period
+    write(one_shot, (read(one_shot) | TIMER_INTERRUPT_ENABLE) & !TIMER_PERIODIC);
+    write(TIMER_COMPARATOR + ONE_SHOT_TIMER * TIMER_STRIDE, u64::MAX);
+    write(CONFIGURATION, read(CONFIGURATION) | LEGACY_ROUTING | ENABLE);
+
+    irq::unmask(ONE_SHOT_IRQ);
+    ONE_SHOT_ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Arms the one-shot timer for `deadline` unless an earlier wakeup is already pending.
+/// Does nothing when one-shot interrupts are not enabled.
+pub fn schedule_wakeup(deadline: Instant) {
+    if !ONE_SHOT_ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let deadline_nanos = deadline.since_boot().as_nanos() as u64;
+    let mut armed = ARMED_DEADLINE.load(Ordering::Relaxed);
+    loop {
+        if deadline_nanos >= armed {
+            return; //an earlier interrupt will come anyway and re-arm for us
+        }
+        match ARMED_DEADLINE.compare_exchange(armed, deadline_nanos, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => armed = current,
+        }
+    }
+
+    //a narrow comparator cannot be armed a full wrap ahead; firing early is fine, the handler re-arms
+    let mask = ONE_SHOT_MASK.load(Ordering::Relaxed);
+    let delay = nanos_to_ticks(deadline.duration_since(Instant::now()).as_nanos() as u64).clamp(1, mask / 2);
+    let start = counter();
+    write(TIMER_COMPARATOR + ONE_SHOT_TIMER * TIMER_STRIDE, start.wrapping_add(delay) & mask);
+    //the comparator only fires on an exact match: if the counter got past it before the write
+    //took effect, no interrupt comes and nothing would ever re-arm, so do its work right here
+    if ticks_between(start, counter()) >= delay {
+        expire();
+    }
+}
+
+fn one_shot_handler(_irq: u8) -> IrqReturn {
+    expire();
+    IrqReturn::Handled
+}
+
+//the armed wakeup is due: wake the sleepers and arm for the next one
+fn expire() {
+    ARMED_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+    if let Some(next) = crate::task::timer::wake_expired_sleepers() {
+        schedule_wakeup(next);
+    }
+}
+
+const _: () = assert!(mem::size_of::<HpetTable>() == 56);
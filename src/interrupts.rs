@@ -3,19 +3,26 @@ use x86_64::structures::idt::{
     InterruptStackFrame   //stack to save the state of CPU just before interrupt occurs
 };
 use crate::println;
+use crate::fatal_println;
 use lazy_static::lazy_static; //to make idt stactic since idt on its own is treated as a normal hence its time doesnt live long enough for interrupt handling
 use crate::gdt;
+use crate::apic;
 use pic8259::ChainedPics;
-use spin;
+use crate::sync::IrqSafeMutex;
 use crate::print;
 use x86_64::structures::idt::PageFaultErrorCode;
 use crate::hlt_loop;
+use irq::IrqReturn;
+use crate::percpu;
+use crate::usermode;
+
+pub mod irq; //runtime registration of hardware interrupt handlers
 
 //setting offesets for PIC to the range 32-47
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> = IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 
 lazy_static! {
@@ -25,54 +32,109 @@ lazy_static! {
         unsafe{
             idt.double_fault.set_handler_fn(double_fault_handler) //double fault handler to handler exceptions who do not have a handler in IDT 
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); //to set the stack index for double fault handler in the IDT
-        }
-        idt[InterruptIndex::Timer.as_usize()]
-           .set_handler_fn(timer_interrupt_handler); //call the timer hanlder that was causing double fault exception
 
-        idt[InterruptIndex::Keyboard.as_usize()]
-           .set_handler_fn(keyboard_interrupt_handler); //call the keyboard handler to handler interrupts from keyboard
+            //these can hit at any time, so they must not depend on the interrupted stack being usable
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            idt.debug.set_handler_fn(debug_handler)
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
+        }
+        irq::install_stubs(&mut idt); //every PIC vector goes through the common dispatch stub
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
 
         idt.page_fault.set_handler_fn(page_fault_handler); //add the page fault handler
+        idt.device_not_available.set_handler_fn(device_not_available_handler); //lazy FPU context switching
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler); //e.g. privileged instructions in ring 3
 
         idt
     };
 }
 
 pub fn init_idt() { //func to initialize idt
+    load_idt();
+
+    irq::register(InterruptIndex::Timer.irq(), timer_interrupt_handler)
+        .expect("failed to register timer handler");
+    irq::register(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler)
+        .expect("failed to register keyboard handler");
+}
+
+//loads the shared IDT on the calling CPU without registering the default handlers again
+pub fn load_idt() {
     IDT.load();
 }
 
 //just outputs a message and pretty-prints the interrupt stack frame.
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame){
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    percpu::with_kernel_gs(stack_frame.code_segment, || {
+        println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    });
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !{
+    if usermode::from_user_mode(stack_frame.code_segment) {
+        unsafe { percpu::swap_gs() }; //never returns, so there is nothing to swap back
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    percpu::with_kernel_gs(stack_frame.code_segment, || {
+        fatal_println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+    });
+}
+
+//the processor state is unreliable after a machine check, so there is nothing to return to
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    if usermode::from_user_mode(stack_frame.code_segment) {
+        unsafe { percpu::swap_gs() };
+    }
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    percpu::with_kernel_gs(stack_frame.code_segment, || {
+        fatal_println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    });
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let origin = if usermode::from_user_mode(stack_frame.code_segment) { "user" } else { "kernel" };
+    percpu::with_kernel_gs(stack_frame.code_segment, || {
+        fatal_println!("EXCEPTION: GENERAL PROTECTION FAULT in {} mode", origin);
+        fatal_println!("Error Code: {:#x}", error_code);
+        fatal_println!("{:#?}", stack_frame);
+    });
+    hlt_loop();
+}
+
+//raised by the first FPU/SSE instruction after a context switch set CR0.TS
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    percpu::with_kernel_gs(stack_frame.code_segment, crate::fpu::handle_device_not_available);
+}
+
+//the local APIC raises this when an interrupt vanished before it was delivered, no EOI is expected
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
 //added a handler function for the timer interrupt that was causing double fault
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame){
+//the EOI(End of interrupt) the PIC expects is sent by the dispatch stub
+fn timer_interrupt_handler(_irq: u8) -> IrqReturn {
     print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); //PIC expects an EOI(End of interrupt) else it will still be busy processing first timer interrupt
-    }
+    crate::thread::tick(); //the switch itself happens in the dispatch stub, after the EOI
+    IrqReturn::Handled
 }
 
 //the Port type of the x86_64 crate to read a byte from the keyboard’s 
 //data port is called a scancode and it represents the key press/release
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame){
+fn keyboard_interrupt_handler(_irq: u8) -> IrqReturn {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    IrqReturn::Handled
 }
 
 
@@ -83,10 +145,12 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    percpu::with_kernel_gs(stack_frame.code_segment, || {
+        fatal_println!("EXCEPTION: PAGE FAULT");
+        fatal_println!("Accessed Address: {:?}", Cr2::read());
+        fatal_println!("Error Code: {:?}", error_code);
+        fatal_println!("{:#?}", stack_frame);
+    });
     hlt_loop();
 }
 
@@ -111,7 +175,8 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    //line number on the PIC, as used by the irq dispatch table
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
\ No newline at end of file
@@ -0,0 +1,266 @@
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use super::{PICS, PIC_1_OFFSET};
+
+pub const IRQ_LINES: usize = 16; //8 lines on the master PIC plus 8 on the slave PIC
+pub const MAX_HANDLERS_PER_IRQ: usize = 4; //how many drivers may share a single line
+
+/// Tells the dispatcher whether a handler recognised the interrupt as coming from its device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+/// A driver callback, invoked with the IRQ line that fired.
+pub type IrqHandler = fn(irq: u8) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine(u8),
+    LineFull(u8),
+    NotRegistered,
+}
+
+/// Returned by `register` and needed to remove the handler again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    irq: u8,
+    slot: usize,
+    generation: u64, //of the slot, so a stale handle cannot remove a later handler
+}
+
+impl IrqHandle {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    handler: Option<IrqHandler>,
+    generation: u64, //bumped whenever the handler is removed
+}
+
+//fixed size slots so that handlers can be registered before the heap exists
+type HandlerSlots = [Slot; MAX_HANDLERS_PER_IRQ];
+
+const EMPTY_SLOT: Slot = Slot { handler: None, generation: 0 };
+const EMPTY_LINE: Mutex<HandlerSlots> = Mutex::new([EMPTY_SLOT; MAX_HANDLERS_PER_IRQ]);
+static HANDLERS: [Mutex<HandlerSlots>; IRQ_LINES] = [EMPTY_LINE; IRQ_LINES];
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES]; //interrupts no registered handler claimed
+static SPURIOUS_MASTER: AtomicU64 = AtomicU64::new(0); //spurious IRQ7s
+static SPURIOUS_SLAVE: AtomicU64 = AtomicU64::new(0); //spurious IRQ15s
+
+crate::per_cpu! {
+    //how many IRQ handlers are running on this CPU, they may nest
+    static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+//8259 command ports and the OCW3/EOI command bytes
+const MASTER_COMMAND: u16 = 0x20;
+const SLAVE_COMMAND: u16 = 0xA0;
+const READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Adds `handler` to the handlers of the given IRQ line (0-15).
+///
+/// Several handlers may share a line; all of them are called on every interrupt.
+pub fn register(irq: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    let line = HANDLERS.get(irq as usize).ok_or(IrqError::InvalidLine(irq))?;
+
+    //the dispatcher locks the same slots, so an interrupt must not arrive while we hold them
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slots = line.lock();
+        let slot = slots
+            .iter()
+            .position(|s| s.handler.is_none())
+            .ok_or(IrqError::LineFull(irq))?;
+        slots[slot].handler = Some(handler);
+        Ok(IrqHandle { irq, slot, generation: slots[slot].generation })
+    })
+}
+
+/// Removes a handler previously added with `register`.
+///
+/// Fails with `NotRegistered` if the handler was already removed, even if the slot got reused.
+pub fn unregister(handle: IrqHandle) -> Result<(), IrqError> {
+    let line = HANDLERS
+        .get(handle.irq as usize)
+        .ok_or(IrqError::InvalidLine(handle.irq))?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slots = line.lock();
+        let slot = &mut slots[handle.slot];
+        if slot.handler.is_none() || slot.generation != handle.generation {
+            return Err(IrqError::NotRegistered);
+        }
+        slot.handler = None;
+        slot.generation += 1;
+        Ok(())
+    })
+}
+
+//data ports of the PICs, reading and writing them accesses the interrupt mask
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_DATA: u16 = 0xA1;
+const CASCADE_IRQ: u8 = 2;
+
+fn set_masked(irq: u8, masked: bool) {
+    let (port, bit) = if irq < 8 { (MASTER_DATA, irq) } else { (SLAVE_DATA, irq - 8) };
+    let mut port: Port<u8> = Port::new(port);
+    let _pics = PICS.lock(); //keep the PIC initialization from running in between
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+}
+
+/// Lets the PIC deliver interrupts on the given line; lines of the slave also need the cascade line.
+pub fn unmask(irq: u8) {
+    set_masked(irq, false);
+    if irq >= 8 {
+        set_masked(CASCADE_IRQ, false);
+    }
+}
+
+/// Stops the PIC from delivering interrupts on the given line.
+pub fn mask(irq: u8) {
+    set_masked(irq, true);
+}
+
+/// Number of interrupts on `irq` that none of the registered handlers claimed.
+pub fn unhandled_count(irq: u8) -> u64 {
+    UNHANDLED
+        .get(irq as usize)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Number of spurious interrupts seen on the master (IRQ7) and slave (IRQ15) PIC.
+pub fn spurious_counts() -> (u64, u64) {
+    (
+        SPURIOUS_MASTER.load(Ordering::Relaxed),
+        SPURIOUS_SLAVE.load(Ordering::Relaxed),
+    )
+}
+
+/// Whether the calling CPU is running registered IRQ handlers right now.
+pub fn in_interrupt() -> bool {
+    HANDLER_DEPTH.get().load(Ordering::Relaxed) > 0
+}
+
+//reads the in-service register, which has a bit set for every IRQ the PIC is currently serving
+fn read_isr(command_port: u16) -> u8 {
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe {
+        port.write(READ_ISR);
+        port.read()
+    }
+}
+
+//a PIC raises its lowest priority line (7) when the request vanished before it could be acknowledged;
+//in that case the line is not marked in-service and must not get an EOI
+fn is_spurious(irq: u8) -> bool {
+    match irq {
+        7 => {
+            if read_isr(MASTER_COMMAND) & (1 << 7) == 0 {
+                SPURIOUS_MASTER.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+            false
+        }
+        15 => {
+            if read_isr(SLAVE_COMMAND) & (1 << 7) == 0 {
+                SPURIOUS_SLAVE.fetch_add(1, Ordering::Relaxed);
+                //the master did not know the slave's IRQ was spurious, so its cascade line still needs an EOI
+                let mut port: Port<u8> = Port::new(MASTER_COMMAND);
+                unsafe { port.write(END_OF_INTERRUPT) };
+                return true;
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+//common path for all hardware interrupts: run every handler on the line, then acknowledge the PIC
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        return;
+    }
+
+    let slots = *HANDLERS[irq as usize].lock(); //copy the slots so handlers run without the lock held
+
+    let mut handled = false;
+    HANDLER_DEPTH.get().fetch_add(1, Ordering::Relaxed);
+    for handler in slots.iter().filter_map(|slot| slot.handler) {
+        if handler(irq) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    HANDLER_DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+    if !handled {
+        UNHANDLED[irq as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+    crate::thread::preempt_if_needed();
+}
+
+//generates one stub per IRQ line since the x86-interrupt ABI gives the handler no vector number
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                crate::percpu::with_kernel_gs(stack_frame.code_segment, || dispatch($irq));
+            }
+        )*
+
+        /// Points all 16 PIC vectors of the IDT at the common dispatch stubs.
+        pub fn install_stubs(idt: &mut InterruptDescriptorTable) {
+            $(
+                idt[usize::from(PIC_1_OFFSET + $irq)].set_handler_fn($name);
+            )*
+        }
+    };
+}
+
+irq_stubs! {
+    0 => irq0_stub,
+    1 => irq1_stub,
+    2 => irq2_stub,
+    3 => irq3_stub,
+    4 => irq4_stub,
+    5 => irq5_stub,
+    6 => irq6_stub,
+    7 => irq7_stub,
+    8 => irq8_stub,
+    9 => irq9_stub,
+    10 => irq10_stub,
+    11 => irq11_stub,
+    12 => irq12_stub,
+    13 => irq13_stub,
+    14 => irq14_stub,
+    15 => irq15_stub,
+}
+
+
+#[test_case]
+fn test_stale_handle_does_not_remove_new_handler() {
+    fn handler(_irq: u8) -> IrqReturn {
+        IrqReturn::NotHandled
+    }
+    let irq = 5; //nothing uses this line
+    let old = register(irq, handler).unwrap();
+    assert_eq!(unregister(old), Ok(()));
+    let new = register(irq, handler).unwrap();
+    assert_eq!(new.slot, old.slot);
+    assert_eq!(unregister(old), Err(IrqError::NotRegistered));
+    assert_eq!(unregister(new), Ok(()));
+}
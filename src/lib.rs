@@ -18,6 +18,21 @@ pub mod gdt; //import GDT(Global Descriptor Table)
 pub mod memory; //import memory module
 pub mod allocator; //import dummy allcator
 pub mod task; //import task
+pub mod sync; //interrupt-safe locking
+pub mod acpi; //ACPI table discovery
+pub mod apic; //local APIC of each processor
+pub mod smp; //starting the other processors
+pub mod percpu; //per-CPU data through the GS base
+pub mod cpu; //CPUID feature detection
+pub mod fpu; //floating point and SIMD state
+pub mod time; //TSC based monotonic clock
+pub mod rtc; //CMOS real-time clock
+pub mod hpet; //high precision event timer
+pub mod usermode; //running code in ring 3
+pub mod syscall; //system call entry and dispatch
+pub mod elf; //loading ELF64 executables
+pub mod process; //user processes and their lifecycle
+pub mod thread; //preemptive kernel threads
 
 //a new testable trait
 pub trait Testable {
@@ -48,8 +63,9 @@ pub fn test_runner(tests: &[&dyn Testable]) { //a function to tests
 
 //panic handler in test mode
 pub fn test_panic_handler(info: &PanicInfo) -> ! { //exit QEMU with an error message on a panic
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    //the panic may have happened while the serial port was locked, so don't use serial_println! here
+    serial::_emergency_print(format_args!("[failed]\n\n"));
+    serial::_emergency_print(format_args!("Error: {}\n\n", info));
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -61,8 +77,16 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! { //this function only used when 'cargo test --lib'
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! { //this function only used when 'cargo test --lib'
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init(); //to setup IDT before running tests
+    //a heap, so that unit tests can spawn tasks on the test executor
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
@@ -92,8 +116,14 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
 
 pub fn init() {
+    percpu::init(0); //the bootstrap processor, needs to come first so that the GDT code can record the TSS
     gdt::init(); //call GDT
+    fpu::init(); //enable FPU and SSE before anything can use them
+    syscall::init(); //needs the user segments of the GDT
     interrupts::init_idt();  //call IDT from interrupt.rs
+    time::init(); //calibrate the TSC while interrupts are still off
+    rtc::init(); //wall-clock time at boot
+    task::timer::init(); //let timer ticks wake sleeping tasks
     unsafe { interrupts::PICS.lock().initialize() }; //initialize 8259 PIC to handle hardware interruptions
     x86_64::instructions::interrupts::enable(); //tells CPU to also listen to interrupt controller now
 }
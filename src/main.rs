@@ -25,7 +25,7 @@ use core::panic::PanicInfo;
 use rust_os::println;
 use bootloader::{BootInfo, entry_point};
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
-use rust_os::task::{Task, simple_executor::SimpleExecutor};
+use rust_os::task::simple_executor::SimpleExecutor;
 use rust_os::task::keyboard;
 use rust_os::task::executor::Executor;
 
@@ -38,7 +38,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {  //start function
     use x86_64::VirtAddr;
 
     println!("Hello World{}", "!");
+    println!("{}", rust_os::cpu::info());
     rust_os::init();
+    println!("Booted at {} UTC", rust_os::rtc::now());
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
@@ -50,9 +52,25 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {  //start function
     allocator::init_heap(&mut mapper, &mut frame_allocator)
             .expect("heap initialization failed");
 
+    match rust_os::hpet::init(&mut mapper, &mut frame_allocator) {
+        Ok(()) => {
+            println!("HPET running at {} Hz", rust_os::hpet::frequency());
+            //sleeps wake on time instead of at the next tick; the HPET takes over the tick too
+            if let Err(err) = rust_os::hpet::enable_one_shot_interrupts(100) {
+                println!("no HPET one-shot wakeups: {:?}", err);
+            }
+        }
+        Err(err) => println!("no HPET, staying with the PIT: {:?}", err),
+    }
+
+    match rust_os::smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(started) => println!("{} CPUs online ({} application processors started)", rust_os::smp::cpus_online(), started),
+        Err(err) => println!("SMP initialization failed: {:?}", err),
+    }
+
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses())); //execute on key presses to print keys
+    executor.spawn(example_task());
+    executor.spawn(keyboard::print_keypresses()); //execute on key presses to print keys
     executor.run();
         
 
@@ -69,7 +87,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {  //start function
 #[cfg(not(test))] //use panic handler also on testing
 #[panic_handler] 
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    rust_os::fatal_println!("{}", info); //println! would spin forever if the panic hit while WRITER was locked
     rust_os::hlt_loop();
 }
 
@@ -5,10 +5,29 @@ use x86_64::{
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::{
     PhysAddr,
-    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator}
+    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, FrameDeallocator}
 };
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use x86_64::structures::paging::{mapper::MapToError, PageTableFlags};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqSafeMutex;
+
+//virtual address at which the bootloader mapped all of physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+//the level 4 table the kernel booted with, switched back to when a process goes away
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+
+//whether the slots of that table the kernel still maps into got a level 3 table, see `AddressSpace::new`
+static KERNEL_SLOTS_FILLED: IrqSafeMutex<bool> = IrqSafeMutex::new(false);
+
+/// Physical address of the page kept free for the SMP trampoline, see `smp::init`.
+pub const TRAMPOLINE_FRAME: u64 = 0x8000;
+
+//kernel stacks for other CPUs and threads are mapped here, away from the heap
+const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_REGION_START);
 
 /// Returns a mutable reference to the active level 4 table.
 ///
@@ -32,11 +51,244 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 
 // Initialize a new OffsetPageTable
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (kernel_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_FRAME.store(kernel_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
 
+/// Returns the virtual address through which the given physical address can be accessed.
+///
+/// Only valid after `init`, and only for memory the bootloader mapped or that was mapped with `map_mmio`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Makes a device's register range accessible at `phys_to_virt(phys)` with caching disabled.
+///
+/// The bootloader only maps physical memory that appears in the memory map, so device memory
+/// like the local APIC or HPET registers usually has to be mapped by hand.
+pub fn map_mmio(
+    phys: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end_frame = PhysFrame::containing_address(phys + (size - 1));
+
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            //already reachable through the bootloader's physical memory mapping
+            Err(MapToError::PageAlreadyMapped(_)) | Err(MapToError::ParentEntryHugePage) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(phys_to_virt(phys))
+}
+
+/// Maps a fresh kernel stack of `pages` pages and returns its top.
+///
+/// Every stack is preceded by an unmapped guard page, so an overflow page faults
+/// instead of silently corrupting the neighbouring stack.
+pub fn alloc_stack(
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let guard_start = NEXT_STACK.fetch_add((pages + 1) * 4096, Ordering::Relaxed);
+    let stack_start = Page::<Size4KiB>::containing_address(VirtAddr::new(guard_start + 4096));
+    let stack_end = stack_start + pages;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in Page::range(stack_start, stack_end) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(stack_end.start_address())
+}
+
+
+/// Maps fresh, zeroed frames for `size` bytes at `start` and makes them accessible from ring 3.
+///
+/// `flags` can add e.g. `WRITABLE` or `NO_EXECUTE`; `PRESENT` and `USER_ACCESSIBLE` are always set.
+pub fn map_user_pages(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + (size - 1));
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            //zero through the physical memory mapping, the page itself may end up read-only
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096);
+            mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(())
+}
+
+
+/// A separate set of page tables for a user program.
+///
+/// The kernel's mappings are shared with the boot address space, only the user region
+/// (see `usermode::USER_REGION_START`) starts out empty.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates a new level 4 table that references all kernel level 3 tables of the boot one.
+    ///
+    /// The first call gives the boot table a level 3 table for each region the kernel keeps
+    /// mapping into after boot: kernel stacks, the heap and device memory. Later mappings there
+    /// show up in every address space; other kernel slots must not be filled in after that.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<AddressSpace> {
+        use x86_64::structures::paging::page_table::PageTableIndex;
+        use crate::usermode::{USER_REGION_END, USER_REGION_START};
+
+        let user_start = u16::from(VirtAddr::new(USER_REGION_START).p4_index());
+        let user_end = u16::from(VirtAddr::new(USER_REGION_END).p4_index());
+        let is_kernel_slot = |index: u16| index < user_start || index >= user_end;
+        let kernel_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed)));
+        let kernel: &mut PageTable = unsafe { &mut *phys_to_virt(kernel_frame.start_address()).as_mut_ptr() };
+
+        let mut filled = KERNEL_SLOTS_FILLED.lock();
+        if !*filled {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let growing = [
+                STACK_REGION_START,
+                crate::allocator::HEAP_START as u64,
+                PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed), //`map_mmio`
+            ];
+            for address in growing.iter() {
+                let entry = &mut kernel[VirtAddr::new(*address).p4_index()];
+                if entry.is_unused() {
+                    let level_3_frame = frame_allocator.allocate_frame()?;
+                    unsafe { phys_to_virt(level_3_frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096) };
+                    entry.set_frame(level_3_frame, flags);
+                }
+            }
+            *filled = true;
+        }
+        drop(filled);
+
+        let frame = frame_allocator.allocate_frame()?;
+        let table: &mut PageTable = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() };
+        table.zero();
+        for index in (0..512u16).filter(|&index| is_kernel_slot(index)) {
+            table[PageTableIndex::new(index)] = kernel[PageTableIndex::new(index)].clone();
+        }
+        Some(AddressSpace { level_4_frame: frame })
+    }
+
+    /// A mapper for modifying this address space, whether it is active or not.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr() };
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        unsafe { OffsetPageTable::new(table, offset) }
+    }
+
+    /// The frame holding the level 4 table, as loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Switches the calling CPU to this address space.
+    ///
+    /// The caller must keep the page tables alive as long as they are in use.
+    pub unsafe fn activate(&self) {
+        use x86_64::registers::control::Cr3;
+
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// Returns every frame of the user region, the page tables mapping them and the level 4 table.
+    ///
+    /// The address space must not be active on any CPU.
+    pub fn free(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        use x86_64::structures::paging::page_table::PageTableIndex;
+        use crate::usermode::{USER_REGION_END, USER_REGION_START};
+
+        //level is 4 for the level 4 table; huge pages are never mapped into the user region
+        unsafe fn free_table(
+            frame: PhysFrame,
+            level: u8,
+            only: core::ops::Range<u16>,
+            frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+        ) {
+            let table: &PageTable = &*phys_to_virt(frame.start_address()).as_ptr();
+            for index in only {
+                let entry = &table[PageTableIndex::new(index)];
+                if let Ok(child) = entry.frame() {
+                    if level > 1 {
+                        free_table(child, level - 1, 0..512, frame_deallocator);
+                    } else {
+                        frame_deallocator.deallocate_frame(child);
+                    }
+                }
+            }
+            frame_deallocator.deallocate_frame(frame);
+        }
+
+        let user_start = u16::from(VirtAddr::new(USER_REGION_START).p4_index());
+        let user_end = u16::from(VirtAddr::new(USER_REGION_END).p4_index());
+        unsafe { free_table(self.level_4_frame, 4, user_start..user_end, frame_deallocator) };
+    }
+}
+
+/// Whether `addr` is mapped in the active address space and may be accessed from ring 3.
+pub fn is_user_accessible(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    let (frame, _) = Cr3::read();
+    let mut table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indexes.iter().enumerate() {
+        let entry = &table[*index];
+        //the CPU wants the user bit on every level, not just on the last one
+        if !entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return false;
+        }
+        if level == indexes.len() - 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table = unsafe { &*phys_to_virt(entry.addr()).as_ptr() };
+    }
+    true
+}
+
+/// The level 4 table the kernel booted with.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed)))
+}
+
+/// Switches the calling CPU back to the page tables the kernel booted with.
+pub unsafe fn activate_kernel_address_space() {
+    use x86_64::registers::control::Cr3;
+
+    let (_, flags) = Cr3::read();
+    Cr3::write(kernel_level_4_frame(), flags);
+}
+
+
 //creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
     page: Page,
@@ -69,6 +321,7 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free: Option<PhysFrame>, //returned frames, each one stores the address of the next in its first 8 bytes
 }
 
 impl BootInfoFrameAllocator {
@@ -77,6 +330,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: None,
         }
     }
     /// Returns an iterator over the usable frames specified in the memory map.
@@ -88,8 +342,10 @@ impl BootInfoFrameAllocator {
         // map each region to its address range
         let addr_ranges = usable_regions
             .map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // transform to an iterator of frame start addresses, leaving out the trampoline page
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|&addr| addr != TRAMPOLINE_FRAME);
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
@@ -98,8 +354,24 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free {
+            let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            self.free = if next == NO_FRAME { None } else { Some(PhysFrame::containing_address(PhysAddr::new(next))) };
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
-}
\ No newline at end of file
+}
+
+//marks the end of the free list, 0 could be a valid frame address
+const NO_FRAME: u64 = u64::MAX;
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(NO_FRAME, |next| next.start_address().as_u64());
+        phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(next);
+        self.free = Some(frame);
+    }
+}
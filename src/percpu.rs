@@ -0,0 +1,203 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::sync::IrqSafeMutex;
+use crate::task::executor::TaskQueue;
+
+/// Upper bound on the number of processors the kernel supports.
+pub const MAX_CPUS: usize = 64;
+
+//the block GS points at on each CPU, `this` must stay the first field for `current()`
+#[repr(C)]
+struct CpuLocal {
+    this: AtomicPtr<CpuLocal>, //address of the block itself, so that it can be found through `gs:[0]`
+    cpu_id: AtomicUsize,
+    syscall_stack: AtomicU64, //kernel stack the syscall entry switches to
+    user_stack: AtomicU64, //scratch slot for the user stack pointer during that switch
+    tss: UnsafeCell<TaskStateSegment>, //written by the owning CPU only, see `set_kernel_stack`
+    run_queue: IrqSafeMutex<Option<Arc<TaskQueue>>>,
+}
+
+unsafe impl Sync for CpuLocal {} //the TSS is only touched by the CPU it belongs to
+
+//offsets into the block for assembly code that accesses it through `gs:[offset]`
+pub(crate) const SYSCALL_STACK_OFFSET: usize = offset_of!(CpuLocal, syscall_stack);
+pub(crate) const USER_STACK_OFFSET: usize = offset_of!(CpuLocal, user_stack);
+
+const EMPTY_CPU: CpuLocal = CpuLocal {
+    this: AtomicPtr::new(ptr::null_mut()),
+    cpu_id: AtomicUsize::new(0),
+    syscall_stack: AtomicU64::new(0),
+    user_stack: AtomicU64::new(0),
+    tss: UnsafeCell::new(TaskStateSegment::new()),
+    run_queue: IrqSafeMutex::new(None),
+};
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+
+/// Points the GS base of the calling CPU at its per-CPU block.
+///
+/// Must run on every CPU before anything else in this module is used there. The kernel GS
+/// base, which holds the user GS base while the kernel runs, is cleared so that user mode
+/// never sees a kernel pointer; every path into and out of ring 3 executes `swapgs`.
+pub fn init(cpu_id: usize) {
+    assert!(cpu_id < MAX_CPUS, "CPU id {} exceeds MAX_CPUS", cpu_id);
+
+    let local = &CPU_LOCALS[cpu_id];
+    local.this.store(local as *const CpuLocal as *mut CpuLocal, Ordering::Relaxed);
+    local.cpu_id.store(cpu_id, Ordering::Relaxed);
+    let base = VirtAddr::from_ptr(local);
+    GsBase::write(base);
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+//the calling CPU's block, or `None` before `init` ran on it
+fn current() -> Option<&'static CpuLocal> {
+    if GsBase::read().is_null() {
+        return None; //gs:[0] would read from the null page
+    }
+    let this: *const CpuLocal;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags)) };
+    unsafe { this.as_ref() }
+}
+
+/// Id of the calling CPU, 0 for the bootstrap processor.
+///
+/// Also 0 before `init` ran, which only the bootstrap processor gets to see: application
+/// processors call `init` before anything else.
+pub fn cpu_id() -> usize {
+    current().map_or(0, |local| local.cpu_id.load(Ordering::Relaxed))
+}
+
+/// Exchanges the GS base with the kernel GS base. Only needed around transitions to and from user mode.
+pub unsafe fn swap_gs() {
+    asm!("swapgs", options(nostack, preserves_flags));
+}
+
+/// Runs `f` with the per-CPU block in GS for an interrupt or exception handler: if the
+/// interrupted code ran in ring 3 (see `code_segment`) the GS bases are swapped around it.
+pub fn with_kernel_gs<R>(code_segment: u64, f: impl FnOnce() -> R) -> R {
+    let from_user = crate::usermode::from_user_mode(code_segment);
+    if from_user {
+        unsafe { swap_gs() };
+    }
+    let result = f();
+    if from_user {
+        unsafe { swap_gs() };
+    }
+    result
+}
+
+//the calling CPU's block; before `init` that is the bootstrap processor's
+fn local() -> &'static CpuLocal {
+    &CPU_LOCALS[cpu_id()]
+}
+
+/// A copy of the TSS of the calling CPU.
+pub fn tss() -> TaskStateSegment {
+    unsafe { local().tss.get().read_volatile() }
+}
+
+//called by the GDT code before a CPU loads its TSS: moves the TSS into the CPU's block and
+//returns it for the TSS descriptor, which only records its address
+pub(crate) fn install_tss(tss: TaskStateSegment) -> &'static TaskStateSegment {
+    let local = local();
+    //system calls run on the same stack as interrupts from ring 3
+    local.syscall_stack.store(tss.privilege_stack_table[0].as_u64(), Ordering::Relaxed);
+    unsafe {
+        local.tss.get().write(tss);
+        &*local.tss.get()
+    }
+}
+
+//the stack ring 3 currently enters the kernel on
+pub(crate) fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(current().map_or(0, |local| local.syscall_stack.load(Ordering::Relaxed)))
+}
+
+//called on every thread switch: interrupts and system calls from ring 3 enter on the stack of the
+//thread that left ring 3, so it can be switched away from like any other thread
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    let local = local();
+    local.syscall_stack.store(top.as_u64(), Ordering::Relaxed);
+    //the CPU only reads the TSS when it enters ring 0, which cannot happen on this CPU while we are
+    //here; the TSS is packed, so the entry may not be 8-byte aligned
+    unsafe { ptr::addr_of_mut!((*local.tss.get()).privilege_stack_table[0]).write_unaligned(top) };
+}
+
+/// The task queue of the executor running on the calling CPU.
+pub(crate) fn run_queue() -> Option<Arc<TaskQueue>> {
+    current().and_then(|local| local.run_queue.lock().clone())
+}
+
+//called by the executor when it starts running on this CPU
+pub(crate) fn set_run_queue(queue: Arc<TaskQueue>) {
+    if let Some(local) = current() {
+        *local.run_queue.lock() = Some(queue);
+    }
+}
+
+/// A variable with a separate instance for each CPU, declared with `per_cpu!`.
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// The instance belonging to the calling CPU.
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_id()]
+    }
+
+    /// The instance belonging to the given CPU.
+    pub fn for_cpu(&self, cpu_id: usize) -> &T {
+        &self.slots[cpu_id]
+    }
+}
+
+/// Declares a per-CPU static; the initializer must be a constant expression.
+///
+/// ```ignore
+/// per_cpu! {
+///     static TICKS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// TICKS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            const INIT: $ty = $init;
+            $crate::percpu::PerCpu::new([INIT; $crate::percpu::MAX_CPUS])
+        };
+    };
+}
+
+#[test_case]
+fn test_current_block_of_bootstrap_processor() {
+    assert_eq!(cpu_id(), 0);
+    let local = current().expect("percpu::init did not run");
+    assert!(ptr::eq(local, &CPU_LOCALS[0]));
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(local));
+    assert!(KernelGsBase::read().is_null()); //what ring 3 gets to see after swapgs
+}
+
+#[test_case]
+fn test_per_cpu_instances_are_separate() {
+    per_cpu! {
+        static VALUE: AtomicUsize = AtomicUsize::new(0);
+    }
+    VALUE.get().store(7, Ordering::Relaxed);
+    assert_eq!(VALUE.for_cpu(0).load(Ordering::Relaxed), 7);
+    assert_eq!(VALUE.for_cpu(1).load(Ordering::Relaxed), 0);
+}
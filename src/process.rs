@@ -0,0 +1,302 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{fmt, future::Future, pin::Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Size4KiB};
+use x86_64::VirtAddr;
+use crate::elf::{self, ElfError};
+use crate::memory::{self, AddressSpace};
+use crate::sync::IrqSafeMutex;
+use crate::{per_cpu, usermode};
+
+/// Identifies a process. PIDs are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+/// The kernel itself, parent of every process it spawns. It never appears in the process table.
+pub const KERNEL_PID: Pid = Pid(0);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Something a process can refer to by a small integer, like a file descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handle {
+    Console,
+}
+
+/// The open handles of a process, indexed by handle number.
+#[derive(Debug, Clone, Default)]
+pub struct HandleTable {
+    slots: Vec<Option<Handle>>,
+}
+
+impl HandleTable {
+    /// A table with the console open as handles 0, 1 and 2 (input, output, error).
+    pub fn with_console() -> Self {
+        HandleTable {
+            slots: alloc::vec![Some(Handle::Console), Some(Handle::Console), Some(Handle::Console)],
+        }
+    }
+
+    /// Stores the handle in the lowest free slot and returns its number.
+    pub fn insert(&mut self, handle: Handle) -> usize {
+        match self.slots.iter().position(Option::is_none) {
+            Some(number) => {
+                self.slots[number] = Some(handle);
+                number
+            }
+            None => {
+                self.slots.push(Some(handle));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    pub fn get(&self, number: usize) -> Option<&Handle> {
+        self.slots.get(number).and_then(Option::as_ref)
+    }
+
+    pub fn remove(&mut self, number: usize) -> Option<Handle> {
+        self.slots.get_mut(number).and_then(Option::take)
+    }
+
+    /// Closes every handle.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// Started or ready to start.
+    Running,
+    /// Exited with the given status, waiting for its parent to collect it.
+    Zombie(i64),
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    Load(ElfError),
+    NoSuchProcess,
+    NotAChild,
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        ProcessError::Load(err)
+    }
+}
+
+struct Process {
+    pid: Pid,
+    parent: Option<Pid>, //None once the parent exited; such orphans are reaped as soon as they exit
+    name: String,
+    state: ProcessState,
+    address_space: Option<AddressSpace>, //given up on exit
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+    handles: HandleTable,
+    waiters: Vec<Waker>,
+}
+
+static PROCESSES: IrqSafeMutex<Vec<Process>> = IrqSafeMutex::new(Vec::new());
+
+//address spaces of exited processes, freed the next time a frame allocator is at hand
+static RECLAIM: IrqSafeMutex<Vec<AddressSpace>> = IrqSafeMutex::new(Vec::new());
+
+per_cpu! {
+    //pid of the process running on this CPU, 0 while in the kernel
+    static CURRENT: AtomicU64 = AtomicU64::new(0);
+}
+
+fn find(processes: &mut [Process], pid: Pid) -> Option<&mut Process> {
+    processes.iter_mut().find(|process| process.pid == pid)
+}
+
+/// Loads an executable as a new child of `parent`; it starts running with `run`.
+///
+/// Children of a process inherit its handles, children of the kernel start with the console.
+pub fn spawn<A>(
+    name: &str,
+    executable: &[u8],
+    args: &[&str],
+    parent: Pid,
+    frame_allocator: &mut A,
+) -> Result<Pid, ProcessError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    reclaim(frame_allocator);
+
+    let handles = if parent == KERNEL_PID {
+        HandleTable::with_console()
+    } else {
+        let mut processes = PROCESSES.lock();
+        let parent = find(&mut processes, parent).ok_or(ProcessError::NoSuchProcess)?;
+        parent.handles.clone()
+    };
+
+    let program = elf::load(executable, args, &[], frame_allocator)?;
+    let pid = Pid::new();
+    PROCESSES.lock().push(Process {
+        pid,
+        parent: Some(parent),
+        name: String::from(name),
+        state: ProcessState::Running,
+        address_space: Some(program.address_space),
+        entry: program.entry,
+        stack_pointer: program.stack_pointer,
+        handles,
+        waiters: Vec::new(),
+    });
+    Ok(pid)
+}
+
+/// Frees the memory of processes that exited since the last call.
+pub fn reclaim(frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let spaces = core::mem::take(&mut *RECLAIM.lock());
+    for space in spaces {
+        space.free(frame_deallocator);
+    }
+}
+
+/// Switches to the process's address space and jumps to its entry point in ring 3.
+pub fn run(pid: Pid) -> ! {
+    let (entry, stack_pointer) = {
+        let mut processes = PROCESSES.lock();
+        let process = find(&mut processes, pid).expect("run: no such process");
+        let address_space = process.address_space.as_ref().expect("run: process already exited");
+        CURRENT.get().store(pid.0, Ordering::Relaxed);
+        unsafe { address_space.activate() };
+        (process.entry, process.stack_pointer)
+    };
+    unsafe { usermode::enter_user_mode(entry, stack_pointer) }
+}
+
+//called on every thread switch, since a thread may be switched away from in a system call
+pub(crate) fn set_current(pid: Option<Pid>) {
+    CURRENT.get().store(pid.map_or(0, |pid| pid.0), Ordering::Relaxed);
+}
+
+/// The process running on the calling CPU, if any.
+pub fn current() -> Option<Pid> {
+    match CURRENT.get().load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// Ends the current process: its handles are closed, its memory is queued for reclaiming and
+/// it stays a zombie until its parent collects the exit status with `wait`.
+pub fn exit(status: i64) -> ! {
+    let pid = current().expect("exit: not running a process");
+    {
+        let mut processes = PROCESSES.lock();
+        let process = find(&mut processes, pid).expect("exit: current process missing");
+        process.state = ProcessState::Zombie(status);
+        process.handles.clear();
+        for waiter in process.waiters.drain(..) {
+            waiter.wake();
+        }
+        let orphaned = process.parent.is_none();
+        let address_space = process.address_space.take();
+
+        //we are still on its page tables, leave them before they can be freed
+        unsafe { memory::activate_kernel_address_space() };
+        CURRENT.get().store(0, Ordering::Relaxed);
+        RECLAIM.lock().extend(address_space);
+
+        //nobody is going to wait for zombie children any more, the others become orphans
+        processes.retain(|child| child.parent != Some(pid) || child.state == ProcessState::Running);
+        for child in processes.iter_mut().filter(|child| child.parent == Some(pid)) {
+            child.parent = None;
+        }
+        if orphaned {
+            processes.retain(|process| process.pid != pid);
+        }
+    }
+    crate::thread::exit(); //the thread that ran the process has nothing left to do
+}
+
+/// Collects the exit status of a zombie child, removing it from the process table.
+///
+/// Returns `Ok(None)` if the child is still running.
+pub fn try_wait(parent: Pid, child: Pid) -> Result<Option<i64>, ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let index = processes.iter().position(|process| process.pid == child)
+        .ok_or(ProcessError::NoSuchProcess)?;
+    if processes[index].parent != Some(parent) {
+        return Err(ProcessError::NotAChild);
+    }
+    match processes[index].state {
+        ProcessState::Running => Ok(None),
+        ProcessState::Zombie(status) => {
+            processes.remove(index);
+            Ok(Some(status))
+        }
+    }
+}
+
+/// Waits until a child exits and returns its exit status, see `try_wait`.
+pub fn wait(parent: Pid, child: Pid) -> Wait {
+    Wait { parent, child }
+}
+
+pub struct Wait {
+    parent: Pid,
+    child: Pid,
+}
+
+impl Future for Wait {
+    type Output = Result<i64, ProcessError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        //register before checking, so an exit in between is not missed
+        {
+            let mut processes = PROCESSES.lock();
+            if let Some(process) = find(&mut processes, self.child) {
+                if !process.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    process.waiters.push(cx.waker().clone());
+                }
+            }
+        }
+        match try_wait(self.parent, self.child) {
+            Ok(Some(status)) => Poll::Ready(Ok(status)),
+            Ok(None) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+pub fn state(pid: Pid) -> Option<ProcessState> {
+    PROCESSES.lock().iter().find(|process| process.pid == pid).map(|process| process.state)
+}
+
+pub fn parent(pid: Pid) -> Option<Pid> {
+    PROCESSES.lock().iter().find(|process| process.pid == pid).and_then(|process| process.parent)
+}
+
+pub fn children(pid: Pid) -> Vec<Pid> {
+    PROCESSES.lock().iter().filter(|process| process.parent == Some(pid)).map(|process| process.pid).collect()
+}
+
+/// Name and state of every process, for diagnostics.
+pub fn list() -> Vec<(Pid, String, ProcessState)> {
+    PROCESSES.lock().iter().map(|process| (process.pid, process.name.clone(), process.state)).collect()
+}
@@ -0,0 +1,301 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::interrupts::irq::{self, IrqError, IrqHandle, IrqReturn};
+use crate::sync::IrqSafeMutex;
+use crate::time::Instant;
+
+const RTC_IRQ: u8 = 8;
+
+//CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const CENTURY: u8 = 0x32; //not standardized, but where practically every PC keeps it
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+const STATUS_D: u8 = 0x0D; //read only, left selected between accesses
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7; //status A
+const PERIODIC_INTERRUPT: u8 = 1 << 6; //status B
+const BINARY_MODE: u8 = 1 << 2; //status B, otherwise BCD
+const HOUR_24: u8 = 1 << 1; //status B, otherwise 12 hour clock
+const HOUR_PM: u8 = 1 << 7; //set in the hours register for PM in 12 hour mode
+const DISABLE_NMI: u8 = 1 << 7; //set on the index port while we access the CMOS
+
+//index and data port have to be used as a pair, so they sit behind one lock
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(DISABLE_NMI | register);
+            let value = self.data.read();
+            self.enable_nmi();
+            value
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(DISABLE_NMI | register);
+            self.data.write(value);
+            self.enable_nmi();
+        }
+    }
+
+    //the NMI mask bit sticks until the index port is written again
+    unsafe fn enable_nmi(&mut self) {
+        self.index.write(STATUS_D);
+    }
+}
+
+static CMOS: IrqSafeMutex<Cmos> = IrqSafeMutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0); //RTC reading taken by `init`
+static BOOT_INSTANT_NANOS: AtomicU64 = AtomicU64::new(0); //monotonic time at which it was taken
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_HANDLER: IrqSafeMutex<Option<IrqHandle>> = IrqSafeMutex::new(None); //while the periodic interrupt is on
+
+/// A calendar date and time of day, as kept by the RTC (normally UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> u64 {
+        //days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let (year, month) = if self.month <= 2 {
+            (i64::from(self.year) - 1, i64::from(self.month) + 9)
+        } else {
+            (i64::from(self.year), i64::from(self.month) - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second);
+        seconds.max(0) as u64
+    }
+
+    /// The inverse of `unix_timestamp`.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86_400) as i64 + 719_468;
+        let seconds_of_day = timestamp % 86_400;
+
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+//raw register values, compared between reads to detect an update that happened in the middle
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+fn read_raw(cmos: &mut Cmos) -> RawTime {
+    while cmos.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime([
+        cmos.read(SECONDS),
+        cmos.read(MINUTES),
+        cmos.read(HOURS),
+        cmos.read(DAY),
+        cmos.read(MONTH),
+        cmos.read(YEAR),
+        cmos.read(CENTURY),
+    ])
+}
+
+/// Reads the current date and time straight from the RTC.
+pub fn read() -> DateTime {
+    let mut cmos = CMOS.lock();
+
+    //the update flag may flip right after we checked it, so read until two readings agree
+    let mut raw = read_raw(&mut cmos);
+    loop {
+        let again = read_raw(&mut cmos);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = cmos.read(STATUS_B);
+    drop(cmos);
+
+    let [second, minute, hour, day, month, year, century] = raw.0;
+    let pm = hour & HOUR_PM != 0;
+    let decode = |value: u8| if status_b & BINARY_MODE != 0 { value } else { from_bcd(value) };
+
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        //12 hour clock: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        0 => 20, //no century register, assume we live in the 2000s
+        century => century,
+    };
+
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Reads the RTC once, so that `unix_time` can afterwards be derived from the monotonic clock.
+pub fn init() {
+    let now = read();
+    BOOT_INSTANT_NANOS.store(Instant::now().since_boot().as_nanos() as u64, Ordering::Relaxed);
+    BOOT_UNIX_TIME.store(now.unix_timestamp(), Ordering::Relaxed);
+}
+
+/// Current Unix time in seconds: the RTC reading from `init` plus the uptime since.
+pub fn unix_time() -> u64 {
+    let elapsed_nanos = (Instant::now().since_boot().as_nanos() as u64)
+        .saturating_sub(BOOT_INSTANT_NANOS.load(Ordering::Relaxed));
+    BOOT_UNIX_TIME.load(Ordering::Relaxed) + elapsed_nanos / 1_000_000_000
+}
+
+/// Current wall-clock time, see `unix_time`.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time())
+}
+
+/// Enables the RTC periodic interrupt at `32768 >> (rate - 1)` Hz, `rate` between 3 (8 kHz) and 15 (2 Hz).
+///
+/// Calling it again while the interrupt is enabled only changes the rate.
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+
+    {
+        let mut handler = PERIODIC_HANDLER.lock();
+        if handler.is_none() {
+            *handler = Some(irq::register(RTC_IRQ, periodic_interrupt_handler)?);
+        }
+    }
+    {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        cmos.read(STATUS_C); //clear a pending interrupt so the next one can be raised
+    }
+    irq::unmask(RTC_IRQ);
+    Ok(())
+}
+
+/// Stops the RTC periodic interrupt and removes its handler. Does nothing if it is not enabled.
+pub fn disable_periodic_interrupt() {
+    {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+        cmos.read(STATUS_C);
+    }
+    if let Some(handle) = PERIODIC_HANDLER.lock().take() {
+        irq::unregister(handle).expect("RTC handler was removed behind our back");
+    }
+}
+
+/// Number of periodic interrupts received since `enable_periodic_interrupt`.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn periodic_interrupt_handler(_irq: u8) -> IrqReturn {
+    //the RTC raises no further interrupts until status C has been read
+    let status_c = CMOS.lock().read(STATUS_C);
+    if status_c & PERIODIC_INTERRUPT == 0 {
+        return IrqReturn::NotHandled;
+    }
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqReturn::Handled
+}
+
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let date = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    assert_eq!(date.unix_timestamp(), 1_709_213_862);
+    assert_eq!(DateTime::from_unix_timestamp(date.unix_timestamp()), date);
+}
+
+#[test_case]
+fn test_periodic_interrupt() {
+    use core::time::Duration;
+
+    let before = periodic_ticks();
+    enable_periodic_interrupt(10).unwrap(); //64 Hz
+    enable_periodic_interrupt(10).unwrap(); //no second handler
+    let start = Instant::now();
+    while periodic_ticks() < before + 2 && start.elapsed() < Duration::from_millis(500) {
+        x86_64::instructions::hlt();
+    }
+    assert!(periodic_ticks() >= before + 2);
+
+    disable_periodic_interrupt();
+    let stopped = periodic_ticks();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(50) {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(periodic_ticks(), stopped);
+    assert!(PERIODIC_HANDLER.lock().is_none());
+}
@@ -1,12 +1,12 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
 
 lazy_static! { //to ensure the init method is called exactly once on its first use
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new(serial_port)
     };
 }
 
@@ -14,14 +14,27 @@ lazy_static! { //to ensure the init method is called exactly once on its first u
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts; //import x86_64 interrupt handling utilities
-
-    interrupts::without_interrupts(|| { // without_interrupts function takes a closure and executes it in an interrupt-free environment
-        SERIAL1
-            .lock() //no interrupts as long as the Mutex is locked
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+
+    SERIAL1
+        .lock() //no interrupts as long as the Mutex is locked
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+const EMERGENCY_LOCK_ATTEMPTS: usize = 10_000; //same budget as the VGA emergency path
+
+//prints through SERIAL1 if it can be locked, otherwise talks to the already initialized port directly
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(mut serial) = SERIAL1.try_lock_for(EMERGENCY_LOCK_ATTEMPTS) {
+        let _ = serial.write_fmt(args);
+        return;
+    }
+
+    let mut serial_port = unsafe { SerialPort::new(0x3F8) }; //no init, that would reset the port under the lock holder
+    let _ = serial_port.write_fmt(args);
 }
 
 ///prints to the host through the serial interface.
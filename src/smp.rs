@@ -0,0 +1,282 @@
+use core::arch::global_asm;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+use crate::{acpi, apic, fpu, gdt, interrupts, memory, percpu, syscall};
+
+//physical page the trampoline is copied to; APs start there in real mode after the startup IPI
+const TRAMPOLINE_BASE: u64 = memory::TRAMPOLINE_FRAME;
+const TRAMPOLINE_PAGE: u8 = (TRAMPOLINE_BASE / 0x1000) as u8;
+
+const AP_STACK_PAGES: u64 = 16; //kernel stack of each application processor
+const IST_STACK_PAGES: u64 = (gdt::IST_STACK_SIZE / 4096) as u64;
+const PRIVILEGE_STACK_PAGES: u64 = (gdt::PRIVILEGE_STACK_SIZE / 4096) as u64;
+
+//how long to wait for an AP to report in after its startup IPIs, in io_delay units (~1µs)
+const AP_STARTUP_TIMEOUT: usize = 1_000_000;
+
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1); //the bootstrap processor is always running
+
+//the trampoline brings an AP from real mode through protected mode into long mode, using the
+//BSP's page tables, and then calls `ap_entry` on its own stack. It is assembled for the kernel
+//image but only ever executed from the copy at TRAMPOLINE_BASE, so every absolute address in it
+//is computed relative to that copy.
+global_asm!(
+    r#"
+    .set TRAMPOLINE_BASE, {trampoline_base}
+
+    .section .text.ap_trampoline, "ax"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+
+    lgdtl (ap_trampoline_gdt_pointer - ap_trampoline_start + TRAMPOLINE_BASE)
+    movl %cr0, %eax
+    orl $1, %eax                            # protection enable
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_trampoline_protected - ap_trampoline_start + TRAMPOLINE_BASE)
+
+    .code32
+ap_trampoline_protected:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+
+    movl %cr4, %eax
+    orl $(1 << 5), %eax                     # physical address extension
+    movl %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start + TRAMPOLINE_BASE), %eax
+    movl %eax, %cr3
+
+    movl $0xC0000080, %ecx                  # EFER
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax       # long mode enable, no-execute enable
+    wrmsr
+
+    movl %cr0, %eax
+    orl $((1 << 31) | (1 << 16)), %eax      # paging, write protect
+    movl %eax, %cr0
+    ljmpl $0x18, $(ap_trampoline_long - ap_trampoline_start + TRAMPOLINE_BASE)
+
+    .code64
+ap_trampoline_long:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+
+    movq (ap_trampoline_stack - ap_trampoline_start + TRAMPOLINE_BASE), %rsp
+    movq (ap_trampoline_argument - ap_trampoline_start + TRAMPOLINE_BASE), %rdi
+    movq (ap_trampoline_entry - ap_trampoline_start + TRAMPOLINE_BASE), %rax
+    callq *%rax
+1:
+    hlt
+    jmp 1b
+
+    .balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF                # 32-bit code
+    .quad 0x00CF92000000FFFF                # 32-bit data
+    .quad 0x00209A0000000000                # 64-bit code
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+    .long ap_trampoline_gdt - ap_trampoline_start + TRAMPOLINE_BASE
+
+    .balign 8
+    .global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+    .global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+    .global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+    .global ap_trampoline_argument
+ap_trampoline_argument:
+    .quad 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+    .text
+    "#,
+    trampoline_base = const TRAMPOLINE_BASE,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+}
+
+//everything an AP needs to set itself up, handed over through the trampoline
+#[repr(C)]
+struct ApStartup {
+    cpu_id: usize,
+    ist_stacks: [VirtAddr; gdt::IST_STACK_COUNT],
+    privilege_stack: VirtAddr,
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    NoMadt,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SmpError::MapFailed(err)
+    }
+}
+
+/// Number of processors that have finished booting, including the bootstrap processor.
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+/// Starts all enabled application processors listed in the ACPI MADT.
+///
+/// Each AP gets its own kernel stack, IST stacks, GDT and TSS, loads the shared IDT and
+/// then parks in `idle_loop`. Returns the number of APs that came up.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, SmpError> {
+    let madt = acpi::madt().ok_or(SmpError::NoMadt)?;
+    apic::init(madt.local_apic_address, mapper, frame_allocator)?;
+    let bsp_id = apic::id();
+
+    install_trampoline(mapper, frame_allocator)?;
+
+    let mut started = 0;
+    for (cpu_id, lapic) in madt
+        .local_apics
+        .iter()
+        .filter(|lapic| lapic.enabled && lapic.apic_id != bsp_id)
+        .enumerate()
+        .take(percpu::MAX_CPUS - 1)
+    {
+        let startup = ApStartup {
+            cpu_id: cpu_id + 1, //0 is the bootstrap processor
+            ist_stacks: [
+                memory::alloc_stack(IST_STACK_PAGES, mapper, frame_allocator)?,
+                memory::alloc_stack(IST_STACK_PAGES, mapper, frame_allocator)?,
+                memory::alloc_stack(IST_STACK_PAGES, mapper, frame_allocator)?,
+                memory::alloc_stack(IST_STACK_PAGES, mapper, frame_allocator)?,
+            ],
+            privilege_stack: memory::alloc_stack(PRIVILEGE_STACK_PAGES, mapper, frame_allocator)?,
+        };
+        let stack = memory::alloc_stack(AP_STACK_PAGES, mapper, frame_allocator)?;
+
+        if start_ap(lapic.apic_id, stack, Box::new(startup)) {
+            started += 1;
+        } else {
+            crate::println!("WARNING: CPU with APIC id {} did not start", lapic.apic_id);
+        }
+    }
+    Ok(started)
+}
+
+//copies the trampoline to low memory and identity maps it, so it survives the AP enabling paging
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(err) => return Err(err),
+    }
+
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+        let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE)).as_mut_ptr::<u8>();
+        ptr::copy_nonoverlapping(start, dest, len);
+
+        let (level_4_table, _) = Cr3::read();
+        //the trampoline loads CR3 while still in protected mode, with a 32-bit register
+        assert!(level_4_table.start_address().as_u64() < 1 << 32, "level 4 table above 4 GiB");
+        write_trampoline_field(addr_of!(ap_trampoline_cr3), level_4_table.start_address().as_u64());
+        write_trampoline_field(addr_of!(ap_trampoline_entry), ap_entry as usize as u64);
+    }
+    Ok(())
+}
+
+//writes a field of the trampoline's copy at TRAMPOLINE_BASE, given the field's symbol in the kernel image
+unsafe fn write_trampoline_field(field: *const u8, value: u64) {
+    let offset = field as u64 - addr_of!(ap_trampoline_start) as u64;
+    let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE + offset));
+    dest.as_mut_ptr::<u64>().write_volatile(value);
+}
+
+//a short delay of roughly one microsecond, by writing to an unused port
+fn io_delay(count: usize) {
+    use x86_64::instructions::port::Port;
+
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..count {
+        unsafe { port.write(0) };
+    }
+}
+
+//INIT-SIPI-SIPI sequence; returns whether the AP reported in before the timeout
+fn start_ap(apic_id: u8, stack: VirtAddr, startup: Box<ApStartup>) -> bool {
+    let online_before = cpus_online();
+    unsafe {
+        write_trampoline_field(addr_of!(ap_trampoline_stack), stack.as_u64());
+        write_trampoline_field(addr_of!(ap_trampoline_argument), Box::into_raw(startup) as u64);
+    }
+
+    apic::send_init(apic_id);
+    io_delay(10_000); //10 ms
+    for _ in 0..2 {
+        apic::send_startup(apic_id, TRAMPOLINE_PAGE);
+        for _ in 0..AP_STARTUP_TIMEOUT / 2 {
+            if cpus_online() > online_before {
+                return true;
+            }
+            io_delay(1);
+        }
+    }
+    false
+}
+
+//first Rust code that runs on an application processor
+extern "C" fn ap_entry(startup: *mut ApStartup) -> ! {
+    let startup = unsafe { Box::from_raw(startup) };
+
+    percpu::init(startup.cpu_id);
+    gdt::init_ap(startup.ist_stacks, startup.privilege_stack);
+    fpu::init();
+    syscall::init();
+    interrupts::load_idt();
+    apic::enable();
+
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst); //only now the BSP may reuse the trampoline
+    idle_loop(startup.cpu_id)
+}
+
+/// The loop a CPU runs when it has nothing else to do.
+pub fn idle_loop(_cpu_id: usize) -> ! {
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop()
+}
@@ -0,0 +1,145 @@
+use core::ops::{Deref, DerefMut};
+use core::mem::ManuallyDrop;
+use x86_64::instructions::interrupts;
+#[cfg(debug_assertions)]
+use core::{panic::Location, ptr, sync::atomic::{AtomicPtr, Ordering}};
+
+//how often `lock` retries before a debug build assumes nobody is going to release the lock
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: usize = 100_000_000;
+
+/// A spinlock that keeps interrupts disabled for as long as it is held.
+///
+/// Use this for any data that is also touched from an interrupt handler: if the handler
+/// fired while the lock was held on the same CPU, it would spin forever.
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(debug_assertions)]
+    holder: AtomicPtr<Location<'static>>, //where the current guard was taken, for deadlock reports
+}
+
+/// Releases the lock and restores the previous interrupt state when dropped.
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    #[cfg(debug_assertions)]
+    lock: &'a IrqSafeMutex<T>,
+    were_enabled: bool, //interrupt flag before we disabled it
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: spin::Mutex::new(value),
+            #[cfg(debug_assertions)]
+            holder: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is acquired.
+    ///
+    /// In debug builds, panics with the location of the current holder if the lock
+    /// does not become free in a reasonable time.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable(); //disable before locking so that no handler can run while we hold it
+
+        #[cfg(debug_assertions)]
+        let guard = {
+            let mut spins = 0;
+            loop {
+                if let Some(guard) = self.inner.try_lock() {
+                    break guard;
+                }
+                spins += 1;
+                if spins == DEADLOCK_SPINS {
+                    self.report_deadlock();
+                }
+                core::hint::spin_loop();
+            }
+        };
+        #[cfg(not(debug_assertions))]
+        let guard = self.inner.lock();
+
+        self.acquired(guard, were_enabled)
+    }
+
+    /// Tries to acquire the lock once, returning `None` if it is already held.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.acquired(guard, were_enabled)),
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Like `try_lock`, but retries up to `attempts` times so a holder on another CPU gets
+    /// a chance to finish. Meant for paths that must not block forever, such as a panic.
+    #[track_caller]
+    pub fn try_lock_for(&self, attempts: usize) -> Option<IrqSafeMutexGuard<T>> {
+        for _ in 0..attempts {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    #[track_caller]
+    fn acquired<'a>(&'a self, guard: spin::MutexGuard<'a, T>, were_enabled: bool) -> IrqSafeMutexGuard<'a, T> {
+        #[cfg(debug_assertions)]
+        self.holder.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            #[cfg(debug_assertions)]
+            lock: self,
+            were_enabled,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn report_deadlock(&self) -> ! {
+        let holder = self.holder.load(Ordering::Relaxed);
+        match unsafe { holder.as_ref() } {
+            Some(location) => panic!("deadlock: lock at {} is still held from {}", Location::caller(), location),
+            None => panic!("deadlock: lock at {} is never released", Location::caller()),
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.holder.store(ptr::null_mut(), Ordering::Relaxed);
+
+        unsafe { ManuallyDrop::drop(&mut self.guard) }; //unlock first, only then let interrupts in again
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
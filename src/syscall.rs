@@ -0,0 +1,162 @@
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::{gdt, memory, percpu, print, process, thread, usermode};
+use crate::time::Instant;
+
+/// System call numbers, passed in `rax`.
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_SLEEP: u64 = 2;
+pub const SYS_EXIT: u64 = 3;
+
+//the longest string a single write may print
+const MAX_WRITE_LEN: u64 = 4096;
+
+/// Errors returned to user space as negative values in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    BadAddress = -14,
+    InvalidArgument = -22,
+    NoSuchSyscall = -38,
+}
+
+/// Arguments in the order they are passed: `rdi`, `rsi`, `rdx`, `r10`, `r8`.
+pub type SyscallArgs = [u64; 5];
+pub type SyscallHandler = fn(args: &SyscallArgs) -> Result<u64, SyscallError>;
+
+//indexed by system call number
+static SYSCALL_TABLE: [SyscallHandler; 4] = [sys_write, sys_yield, sys_sleep, sys_exit];
+
+//entered through the syscall instruction with interrupts masked: user rip in rcx, user rflags in r11,
+//and still on the user stack. Switches to the kernel stack of the running thread, which
+//`percpu::set_kernel_stack` keeps up to date, so a handler may block or yield; it then moves the
+//arguments into the System V positions and returns with sysretq. Everything but rax, rcx and r11
+//is preserved.
+global_asm!(
+    r#"
+    .global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{syscall_stack}]
+    push qword ptr gs:[{user_stack}]
+    push rcx
+    push r11
+    push rdi
+    push rsi
+    push rdx
+    push r8
+    push r9
+    push r10
+    sub rsp, 8                          # 9 pushes, realign to 16 bytes for the call
+
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    call {dispatch}
+
+    add rsp, 8
+    pop r10
+    pop r9
+    pop r8
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+    "#,
+    user_stack = const percpu::USER_STACK_OFFSET,
+    syscall_stack = const percpu::SYSCALL_STACK_OFFSET,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables the syscall instruction on the calling CPU. Needs to run after `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not fit the syscall/sysret requirements");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    //keep interrupts off until we are on the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn syscall_dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
+    let args = [arg0, arg1, arg2, arg3, arg4];
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(&args),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(err) => err as i64 as u64,
+    }
+}
+
+//checks that [ptr, ptr + len) lies within user memory, so user space cannot make us read kernel
+//data, and that all of it is mapped, since a page fault in the kernel is fatal
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if ptr < usermode::USER_REGION_START || end > usermode::USER_REGION_END {
+        return Err(SyscallError::BadAddress);
+    }
+    let mut page = ptr & !0xFFF;
+    while page < end {
+        if !memory::is_user_accessible(VirtAddr::new(page)) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += 4096;
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+//write(buffer, length): prints UTF-8 text to the console, returns the number of bytes written
+fn sys_write(args: &SyscallArgs) -> Result<u64, SyscallError> {
+    let [ptr, len, ..] = *args;
+    if len > MAX_WRITE_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let text = core::str::from_utf8(user_slice(ptr, len)?).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+//yield(): gives up the rest of the time slice
+fn sys_yield(_args: &SyscallArgs) -> Result<u64, SyscallError> {
+    thread::yield_now();
+    Ok(0)
+}
+
+//sleep(milliseconds): blocks the caller for at least the given time
+fn sys_sleep(args: &SyscallArgs) -> Result<u64, SyscallError> {
+    let deadline = Instant::now()
+        .checked_add(Duration::from_millis(args[0]))
+        .ok_or(SyscallError::InvalidArgument)?;
+    thread::sleep_until(deadline);
+    x86_64::instructions::interrupts::disable(); //sysret restores the user's flags, but we are still on the kernel stack
+    Ok(0)
+}
+
+//exit(code): ends the calling process, does not return
+fn sys_exit(args: &SyscallArgs) -> Result<u64, SyscallError> {
+    process::exit(args[0] as i64);
+}
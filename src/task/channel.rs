@@ -0,0 +1,6 @@
+//! Async channels between tasks. The sending side of each of them never blocks and never
+//! allocates, so interrupt handlers can use it too.
+
+pub mod mpsc; //many senders, one receiver, bounded
+pub mod oneshot; //a single value, sent once
+pub mod broadcast; //every receiver sees every value
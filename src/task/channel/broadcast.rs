@@ -0,0 +1,184 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use crate::sync::IrqSafeMutex;
+
+/// Nobody is subscribed, the value is handed back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind and missed this many values; the next receive continues with the oldest one kept.
+    Lagged(u64),
+    Closed, //every sender is gone and all values were received
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+struct Inner<T> {
+    buffer: VecDeque<T>, //the last `capacity` values, allocated up front
+    capacity: usize,
+    first_seq: u64, //sequence number of buffer[0]
+    next_seq: u64, //sequence number the next value gets
+    senders: usize,
+    receivers: usize,
+    wakers: Vec<Waker>,
+}
+
+impl<T: Clone> Inner<T> {
+    fn try_recv(&self, next: &mut u64) -> Result<T, TryRecvError> {
+        if *next < self.first_seq {
+            let missed = self.first_seq - *next;
+            *next = self.first_seq;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        if *next == self.next_seq {
+            return Err(if self.senders == 0 { TryRecvError::Closed } else { TryRecvError::Empty });
+        }
+        let value = self.buffer[(*next - self.first_seq) as usize].clone();
+        *next += 1;
+        Ok(value)
+    }
+}
+
+type Shared<T> = Arc<IrqSafeMutex<Inner<T>>>;
+
+/// Creates a channel whose receivers each get every value sent after they subscribed, as
+/// long as they keep up: only the last `capacity` values are kept.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel needs a capacity of at least one");
+    let shared = Arc::new(IrqSafeMutex::new(Inner {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        first_seq: 0,
+        next_seq: 0,
+        senders: 1,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0 })
+}
+
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+pub struct Receiver<T> {
+    shared: Shared<T>,
+    next: u64, //sequence number of the next value to receive
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to every receiver and returns how many there are. Usable from interrupt handlers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (receivers, wakers) = {
+            let mut inner = self.shared.lock();
+            if inner.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if inner.buffer.len() == inner.capacity {
+                inner.buffer.pop_front(); //overwrite the oldest value, slow receivers see Lagged
+                inner.first_seq += 1;
+            }
+            inner.buffer.push_back(value);
+            inner.next_seq += 1;
+            (inner.receivers, core::mem::take(&mut inner.wakers))
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+        Ok(receivers)
+    }
+
+    /// A new receiver that gets the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut inner = self.shared.lock();
+        inner.receivers += 1;
+        Receiver { shared: self.shared.clone(), next: inner.next_seq }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut inner = self.shared.lock();
+            inner.senders -= 1;
+            if inner.senders > 0 {
+                return;
+            }
+            core::mem::take(&mut inner.wakers)
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let inner = self.shared.lock();
+        inner.try_recv(&mut self.next)
+    }
+
+    /// Waits for the next value.
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+}
+
+//a copy at the same position in the channel
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().receivers += 1;
+        Receiver { shared: self.shared.clone(), next: self.next }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receivers -= 1;
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for RecvFuture<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let receiver = &mut *self.receiver;
+        let mut inner = receiver.shared.lock();
+        match inner.try_recv(&mut receiver.next) {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Empty) => {
+                if !inner.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    inner.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
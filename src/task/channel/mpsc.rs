@@ -0,0 +1,271 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use crate::sync::IrqSafeMutex;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T), //the receiver is gone
+}
+
+/// The receiver is gone, the value is handed back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed, //empty and every sender is gone
+}
+
+struct Inner<T> {
+    queue: VecDeque<T>, //allocated up front, so sending from an interrupt handler never allocates
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+    sender_wakers: VecDeque<(u64, Waker)>, //senders waiting for space, by the id of their future
+    next_waiter_id: u64,
+}
+
+type Shared<T> = Arc<IrqSafeMutex<Inner<T>>>;
+
+/// Creates a channel holding at most `capacity` values that have not been received yet.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel needs a capacity of at least one");
+    let shared = Arc::new(IrqSafeMutex::new(Inner {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        receiver_waker: None,
+        sender_wakers: VecDeque::new(),
+        next_waiter_id: 0,
+    }));
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+pub struct Receiver<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Sender<T> {
+    /// Queues `value` if there is space. Usable from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let waker = {
+            let mut inner = self.shared.lock();
+            if !inner.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if inner.queue.len() == inner.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            inner.queue.push_back(value);
+            inner.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Waits for space in the channel and queues `value`.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture { sender: self, value: Some(value), waiter: None }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.shared.lock();
+            inner.senders -= 1;
+            if inner.senders > 0 {
+                return;
+            }
+            inner.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake(); //so it sees the channel is closed
+        }
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Option<u64>, //id in `sender_wakers` once it had to wait
+}
+
+impl<T> Unpin for SendFuture<'_, T> {} //the value is moved in and out, never pinned
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let value = self.value.take().expect("SendFuture polled after completion");
+        match self.sender.try_send(value) {
+            Ok(()) => {
+                self.stop_waiting();
+                Poll::Ready(Ok(()))
+            }
+            Err(TrySendError::Closed(value)) => {
+                self.stop_waiting();
+                Poll::Ready(Err(SendError(value)))
+            }
+            Err(TrySendError::Full(value)) => {
+                let mut inner = self.sender.shared.lock();
+                //the receiver may have made room since try_send, retry before sleeping
+                if inner.queue.len() < inner.capacity {
+                    drop(inner);
+                    self.value = Some(value);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let id = match self.waiter {
+                    Some(id) => id,
+                    None => {
+                        let id = inner.next_waiter_id;
+                        inner.next_waiter_id += 1;
+                        id
+                    }
+                };
+                match inner.sender_wakers.iter_mut().find(|(waiter, _)| *waiter == id) {
+                    Some((_, waker)) => waker.clone_from(cx.waker()),
+                    None => inner.sender_wakers.push_back((id, cx.waker().clone())),
+                }
+                drop(inner);
+                self.waiter = Some(id);
+                self.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> SendFuture<'_, T> {
+    //takes this future off the wait list; returns whether it was woken but not polled since
+    fn stop_waiting(&mut self) -> bool {
+        let id = match self.waiter.take() {
+            Some(id) => id,
+            None => return false,
+        };
+        let mut inner = self.sender.shared.lock();
+        match inner.sender_wakers.iter().position(|(waiter, _)| *waiter == id) {
+            Some(index) => {
+                inner.sender_wakers.remove(index);
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if !self.stop_waiting() {
+            return;
+        }
+        //we were woken for a free slot we will not use now, so the next sender gets it
+        let waker = {
+            let mut inner = self.sender.shared.lock();
+            if inner.queue.len() < inner.capacity {
+                inner.sender_wakers.pop_front().map(|(_, waker)| waker)
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let (value, waker) = {
+            let mut inner = self.shared.lock();
+            match inner.queue.pop_front() {
+                Some(value) => (value, inner.sender_wakers.pop_front().map(|(_, waker)| waker)),
+                None if inner.senders == 0 => return Err(TryRecvError::Closed),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake(); //there is room for one more value now
+        }
+        Ok(value)
+    }
+
+    /// Waits for the next value; `None` once the channel is empty and every sender is gone.
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.shared.lock().receiver_waker = Some(cx.waker().clone());
+        //a value may have arrived before the waker was in place
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut inner = self.shared.lock();
+            inner.receiver_alive = false;
+            inner.queue.clear();
+            core::mem::take(&mut inner.sender_wakers)
+        };
+        for (_, waker) in wakers {
+            waker.wake(); //their sends fail now
+        }
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
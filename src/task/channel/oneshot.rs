@@ -0,0 +1,97 @@
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use crate::sync::IrqSafeMutex;
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+/// Creates a channel for exactly one value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(IrqSafeMutex::new(Inner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waker: None,
+    }));
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender<T> {
+    shared: Arc<IrqSafeMutex<Inner<T>>>,
+}
+
+/// Resolves to the sent value.
+pub struct Receiver<T> {
+    shared: Arc<IrqSafeMutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver is gone. Usable from interrupt handlers.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut inner = self.shared.lock();
+            if !inner.receiver_alive {
+                return Err(value);
+            }
+            inner.value = Some(value);
+            inner.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.shared.lock();
+            inner.sender_alive = false;
+            inner.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The value if it was sent already.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.lock().value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut inner = self.shared.lock();
+        if let Some(value) = inner.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if !inner.sender_alive {
+            return Poll::Ready(Err(RecvError));
+        }
+        inner.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        inner.receiver_alive = false;
+        inner.waker = None;
+    }
+}
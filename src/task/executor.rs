@@ -1,80 +1,234 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{JoinHandle, Priority, Task, TaskId};
+use super::metrics::{self, Monitor, TaskSnapshot, TaskStats, TaskTable};
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Waker;
-use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll};
 use alloc::task::Wake;
+use crate::sync::IrqSafeMutex;
+use crate::time::Instant;
+
+/// Ids of the tasks that are ready to be polled. Unbounded, so waking never fails.
+pub(crate) type TaskQueue = IrqSafeMutex<ReadyQueues>;
+
+/// How often a single task may be polled in one round before the others get their turn.
+pub const MAX_POLLS_PER_ROUND: usize = 3;
+
+/// A single poll taking longer than this counts in `slow_polls`, unless changed with `set_slow_poll_threshold`.
+pub const DEFAULT_SLOW_POLL_THRESHOLD: Duration = Duration::from_millis(10);
+
+const PRIORITY_LEVELS: usize = 3;
+
+/// One FIFO queue per priority, in wake order.
+pub(crate) struct ReadyQueues {
+    queues: [VecDeque<TaskId>; PRIORITY_LEVELS],
+}
+
+impl ReadyQueues {
+    const fn new() -> Self {
+        ReadyQueues {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    fn push(&mut self, task_id: TaskId, priority: Priority) {
+        self.queues[priority as usize].push_back(task_id);
+    }
+
+    //queues the task ahead of every other task of its priority
+    fn push_front(&mut self, task_id: TaskId, priority: Priority) {
+        self.queues[priority as usize].push_front(task_id);
+    }
+
+    //the longest waiting task of the highest priority
+    fn pop(&mut self) -> Option<TaskId> {
+        self.queues.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<TaskQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    spawn_queue: Arc<IrqSafeMutex<VecDeque<SendTask>>>,
+    stats: Arc<TaskTable>,
+    slow_poll_threshold: Duration,
 }
 
+/// Submits tasks to a running `Executor`; cheap to clone and usable from interrupt handlers.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<IrqSafeMutex<VecDeque<SendTask>>>,
+}
+
+//a task whose future is known to be Send, see `Spawner::spawn`
+struct SendTask(Task);
+
+unsafe impl Send for SendTask {}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    task_queue: Arc<TaskQueue>,
+    queued: AtomicBool, //set while the id is in the queue, so repeated wakes only queue it once
+    stats: Arc<TaskStats>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(IrqSafeMutex::new(ReadyQueues::new())),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(IrqSafeMutex::new(VecDeque::new())),
+            stats: Arc::new(IrqSafeMutex::new(BTreeMap::new())),
+            slow_poll_threshold: DEFAULT_SLOW_POLL_THRESHOLD,
+        }
+    }
+
+    /// A handle for listing this executor's tasks once `run` took it over.
+    pub fn monitor(&self) -> Monitor {
+        Monitor {
+            table: self.stats.clone(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// Name, timing and poll statistics of every live task.
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        metrics::snapshot(&self.stats)
+    }
+
+    pub fn set_slow_poll_threshold(&mut self, threshold: Duration) {
+        self.slow_poll_threshold = threshold;
+    }
+
+    /// A handle for spawning onto this executor once `run` took it over.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    /// Runs `future` as a new task; its output can be awaited through the returned handle.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    pub fn spawn_with_priority<F>(&mut self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::with_join_handle(future);
+        self.spawn_task(task.with_priority(priority));
+        handle
+    }
+
+    pub fn spawn_task(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
+        let stats = Arc::new(TaskStats::new(task.name, priority));
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.stats.lock().insert(task_id, stats.clone());
+        let waker = TaskWaker::new(task_id, priority, self.task_queue.clone(), stats);
+        waker.queue(); //every new task gets polled once
+        self.waker_cache.insert(task_id, waker);
     }
 
-    //execute all tasks in the task_queue
+    //execute all tasks in the task_queue, highest priority first; one call is a round, in which
+    //no task is polled more than MAX_POLLS_PER_ROUND times
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            stats,
+            slow_poll_threshold,
+            ..
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+        let mut polls: BTreeMap<TaskId, usize> = BTreeMap::new();
+        let mut deferred = Vec::new();
+        loop {
+            let task_id = match task_queue.lock().pop() { //not held while polling, the task may wake itself
+                Some(task_id) => task_id,
+                None => break,
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let (task, task_waker) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+                (Some(task), Some(task_waker)) => (task, task_waker),
+                _ => continue, // task no longer exists
+            };
+            let count = polls.entry(task_id).or_insert(0);
+            if *count == MAX_POLLS_PER_ROUND {
+                deferred.push(task_waker.clone()); //still marked as queued, so wakes meanwhile are no-ops
+                continue;
+            }
+            *count += 1;
+            task_waker.queued.store(false, Ordering::Release); //wakes from now on need to queue it again
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            task.register_abort_waker(&waker);
+            let start = Instant::now();
+            let result = task.poll(&mut context);
+            let duration = start.elapsed();
+            task_waker.stats.record_poll(duration, *slow_poll_threshold);
+            match result {
                 Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
+                    // task done -> remove it, its cached waker and its statistics
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    stats.lock().remove(&task_id);
                 }
                 Poll::Pending => {}
             }
         }
+
+        //tasks that used up their polls go first next round, ahead of anything woken later
+        let mut queue = task_queue.lock();
+        for task_waker in deferred.iter().rev() { //keeps them in the order they were deferred
+            queue.push_front(task_waker.task_id, task_waker.priority);
+        }
     }
 
     //run method for executor
     pub fn run(&mut self) -> ! {
+        crate::percpu::set_run_queue(self.task_queue.clone()); //make the queue reachable as this CPU's run queue
         loop {
+            self.accept_spawned();
             self.run_ready_tasks();
             self.sleep_if_idle(); 
         }
     }
 
+    //moves tasks submitted through a `Spawner` into the executor
+    fn accept_spawned(&mut self) {
+        loop {
+            let task = self.spawn_queue.lock().pop_front(); //not held while spawning, which allocates
+            match task {
+                Some(SendTask(task)) => self.spawn_task(task),
+                None => break,
+            }
+        }
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable(); //to prevent any race condition
-        if self.task_queue.is_empty() {
+        if self.task_queue.lock().is_empty() && self.spawn_queue.lock().is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -83,17 +237,52 @@ impl Executor {
 }
 
 
+impl Spawner {
+    /// Queues `future` as a new task; the executor picks it up the next time it looks for work.
+    ///
+    /// Spawning from another CPU does not wake a halted executor, it notices on its next interrupt.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    pub fn spawn_with_priority<F>(&self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        //the wrapper around a Send future only adds Send state, so the task is Send as well
+        let (task, handle) = Task::with_join_handle(future);
+        self.spawn_queue.lock().push_back(SendTask(task.with_priority(priority)));
+        handle
+    }
+}
+
+
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.stats.record_wake();
+        self.queue();
+    }
+
+    fn queue(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.task_queue.lock().push(self.task_id, self.priority);
+        }
     }
 
     //create wakers
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, priority: Priority, task_queue: Arc<TaskQueue>, stats: Arc<TaskStats>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
+            priority,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+            stats,
+        })
     }
 }
 
@@ -0,0 +1,236 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin, ptr};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use core::task::{Context, Poll, Waker};
+use crate::interrupts::irq;
+use crate::per_cpu;
+use crate::sync::IrqSafeMutex;
+
+/// Why a task did not produce an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted, or dropped by its executor, before it finished.
+    Cancelled,
+}
+
+//shared between the spawned task, which fills in the output, and its handle
+struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    finished: bool,
+    detached: bool,
+    waker: Option<Waker>,
+}
+
+//the part of a task that can be reached without knowing its output type
+struct TaskControl {
+    aborted: AtomicBool,
+    //both may be locked from interrupt handlers, which can abort tasks and spawn new ones
+    waker: IrqSafeMutex<Option<Waker>>, //wakes the task itself, so that it notices an abort
+    children: IrqSafeMutex<Vec<Weak<TaskControl>>>,
+}
+
+per_cpu! {
+    //control block of the task being polled on this CPU, new tasks become its children
+    static POLLING: AtomicPtr<TaskControl> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// Resolves to the output of a spawned task once it finished.
+///
+/// Dropping the handle detaches the task: it keeps running and its output is dropped.
+pub struct JoinHandle<T> {
+    state: Arc<IrqSafeMutex<JoinState<T>>>,
+    control: Arc<TaskControl>,
+}
+
+/// Stops a task from anywhere, without owning its `JoinHandle`.
+#[derive(Clone)]
+pub struct AbortHandle {
+    control: Arc<TaskControl>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Lets the task run to completion without anyone waiting for it.
+    pub fn detach(self) {}
+
+    /// Whether the task already returned (or was cancelled), without waiting for it.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// Cancels the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.control.abort();
+    }
+
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle { control: self.control.clone() }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(output) = state.output.take() {
+            return Poll::Ready(output);
+        }
+        assert!(!state.finished, "JoinHandle polled after it returned the output");
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.detached = true;
+        state.output = None;
+        state.waker = None;
+    }
+}
+
+impl AbortHandle {
+    /// Cancels the task and every task spawned while it was being polled, recursively.
+    ///
+    /// The task's future is dropped the next time its executor gets to it, which removes the
+    /// task; anyone awaiting its `JoinHandle` gets `Err(JoinError::Cancelled)`. Aborting a
+    /// finished task does nothing.
+    pub fn abort(&self) {
+        self.control.abort();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.control.aborted.load(Ordering::Acquire)
+    }
+
+    //called by executors before every poll of the task: the waker an abort wakes it with. Kept out
+    //of the task's own poll so that the wrapper never holds on to the waker of a single poll.
+    pub(super) fn register_waker(&self, waker: &Waker) {
+        let mut stored = self.control.waker.lock();
+        if !stored.as_ref().map_or(false, |stored| stored.will_wake(waker)) {
+            *stored = Some(waker.clone());
+        }
+    }
+}
+
+impl TaskControl {
+    fn abort(&self) {
+        if self.aborted.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.abort();
+        }
+    }
+}
+
+//the future a joinable task actually runs: polls the user's future unless aborted and hands
+//the result to the JoinHandle, or `Cancelled` if it is dropped before that
+struct Joinable<F: Future> {
+    future: Option<F>,
+    state: Arc<IrqSafeMutex<JoinState<F::Output>>>,
+    control: Arc<TaskControl>,
+}
+
+impl<F: Future> Joinable<F> {
+    fn finish(&mut self, output: Result<F::Output, JoinError>) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.finished = true;
+            if !state.detached {
+                state.output = Some(output);
+            }
+            state.waker.take()
+        };
+        //wake outside the lock, the handle's task might be polled right away on another CPU
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        //the inner future is never moved: it is only polled in place and dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.future.is_none() {
+            return Poll::Ready(());
+        }
+        if this.control.aborted.load(Ordering::Acquire) {
+            this.future = None;
+            this.finish(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+
+        let control = Arc::as_ptr(&this.control) as *mut TaskControl;
+        let outer = POLLING.get().swap(control, Ordering::Relaxed); //executors may be nested
+        let future = unsafe { Pin::new_unchecked(this.future.as_mut().unwrap()) };
+        let result = future.poll(cx);
+        POLLING.get().store(outer, Ordering::Relaxed);
+
+        match result {
+            Poll::Ready(output) => {
+                this.future = None;
+                this.finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        if self.future.take().is_some() {
+            self.finish(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Wraps `future` so that its output is handed to the returned `JoinHandle`.
+///
+/// If another task is being polled right now, the new task becomes its child and is aborted with it.
+/// Tasks created in an interrupt handler have no parent, whatever task the interrupt cut into.
+pub(super) fn joinable<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(IrqSafeMutex::new(JoinState {
+        output: None,
+        finished: false,
+        detached: false,
+        waker: None,
+    }));
+    let control = Arc::new(TaskControl {
+        aborted: AtomicBool::new(false),
+        waker: IrqSafeMutex::new(None),
+        children: IrqSafeMutex::new(Vec::new()),
+    });
+
+    let parent = if irq::in_interrupt() {
+        ptr::null_mut()
+    } else {
+        POLLING.get().load(Ordering::Relaxed)
+    };
+    if let Some(parent) = unsafe { parent.as_ref() } {
+        if parent.aborted.load(Ordering::Acquire) {
+            control.aborted.store(true, Ordering::Release);
+        } else {
+            let mut children = parent.children.lock();
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&control));
+        }
+    }
+
+    let handle = JoinHandle { state: state.clone(), control: control.clone() };
+    let task = Joinable { future: Some(future), state, control };
+    (task, handle)
+}
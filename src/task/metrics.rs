@@ -0,0 +1,114 @@
+use super::{Priority, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::sync::IrqSafeMutex;
+use crate::time::Instant;
+
+/// What an executor knows about one of its tasks at the time of the snapshot.
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: u64,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub spawned_at: Duration, //since boot
+    pub polls: u64,
+    pub busy_time: Duration, //spent inside `poll`
+    pub longest_poll: Duration,
+    pub slow_polls: u64, //polls that took longer than the executor's threshold
+    pub last_wake: Option<Duration>, //since boot, None if the task was never woken
+}
+
+impl fmt::Display for TaskSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>5} {:<8} {:>8} polls {:>10?} busy {:>10?} max {:>4} slow  {}",
+            self.id,
+            alloc::format!("{:?}", self.priority),
+            self.polls,
+            self.busy_time,
+            self.longest_poll,
+            self.slow_polls,
+            self.name,
+        )
+    }
+}
+
+//counters of a single task, updated by the executor and (for wakes) by its waker
+pub(crate) struct TaskStats {
+    name: &'static str,
+    priority: Priority,
+    spawned_at: Duration,
+    polls: AtomicU64,
+    busy_nanos: AtomicU64,
+    longest_poll_nanos: AtomicU64,
+    slow_polls: AtomicU64,
+    last_wake_nanos: AtomicU64, //0 until the first wake
+}
+
+impl TaskStats {
+    pub(crate) fn new(name: &'static str, priority: Priority) -> Self {
+        TaskStats {
+            name,
+            priority,
+            spawned_at: Instant::now().since_boot(),
+            polls: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            longest_poll_nanos: AtomicU64::new(0),
+            slow_polls: AtomicU64::new(0),
+            last_wake_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record_wake(&self) {
+        let now = Instant::now().since_boot().as_nanos() as u64;
+        self.last_wake_nanos.store(now.max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_poll(&self, duration: Duration, slow_threshold: Duration) {
+        let nanos = duration.as_nanos() as u64;
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.longest_poll_nanos.fetch_max(nanos, Ordering::Relaxed);
+        if duration > slow_threshold {
+            self.slow_polls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self, id: TaskId) -> TaskSnapshot {
+        let last_wake = self.last_wake_nanos.load(Ordering::Relaxed);
+        TaskSnapshot {
+            id: id.0,
+            name: self.name,
+            priority: self.priority,
+            spawned_at: self.spawned_at,
+            polls: self.polls.load(Ordering::Relaxed),
+            busy_time: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+            longest_poll: Duration::from_nanos(self.longest_poll_nanos.load(Ordering::Relaxed)),
+            slow_polls: self.slow_polls.load(Ordering::Relaxed),
+            last_wake: if last_wake == 0 { None } else { Some(Duration::from_nanos(last_wake)) },
+        }
+    }
+}
+
+//the stats of every live task of one executor
+pub(crate) type TaskTable = IrqSafeMutex<BTreeMap<TaskId, Arc<TaskStats>>>;
+
+pub(crate) fn snapshot(table: &TaskTable) -> Vec<TaskSnapshot> {
+    table.lock().iter().map(|(id, stats)| stats.snapshot(*id)).collect()
+}
+
+/// Read-only view of an executor's tasks that stays usable after `run` took the executor over.
+#[derive(Clone)]
+pub struct Monitor {
+    pub(crate) table: Arc<TaskTable>,
+}
+
+impl Monitor {
+    /// The live tasks, ordered by id (which is spawn order).
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        snapshot(&self.table)
+    }
+}
@@ -1,18 +1,42 @@
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
-use core::task::{Context, Poll};
+use core::task::{Context, Poll, Waker};
 use core::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub(crate) struct TaskId(u64);
 
 pub mod keyboard;
 pub struct Task {
     id: TaskId,
+    name: &'static str,
+    priority: Priority,
+    abort: Option<AbortHandle>, //for tasks with a join handle, see `register_abort_waker`
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
+
+/// Scheduling class of a task; ready tasks of a higher class are polled first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low, //background work
+    Normal,
+    High, //e.g. interrupt-driven I/O
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
 pub mod simple_executor;
 pub mod executor;
+pub mod timer;
+pub mod join;
+pub mod metrics;
+pub mod channel;
+pub mod sync;
+
+pub use join::{AbortHandle, JoinError, JoinHandle};
 
 impl TaskId {
     fn new() -> Self {
@@ -23,13 +47,57 @@ impl TaskId {
 
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new<F>(future: F) -> Task
+    where
+        F: Future<Output = ()> + 'static,
+    {
         Task {
             id: TaskId::new(),
+            name: core::any::type_name::<F>(), //names the async fn the future came from
+            priority: Priority::Normal,
+            abort: None,
             future: Box::pin(future),
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    //a task for a future with any output, which can be collected through the returned handle
+    pub fn with_join_handle<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        let mut task = Task::new(future).with_name(core::any::type_name::<F>());
+        task.abort = Some(handle.abort_handle());
+        (task, handle)
+    }
+
+    /// Sets the name shown in executor snapshots.
+    pub fn with_name(mut self, name: &'static str) -> Task {
+        self.name = name;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    //gives an abort a way to wake the task; executors call this before polling it
+    fn register_abort_waker(&self, waker: &Waker) {
+        if let Some(abort) = &self.abort {
+            abort.register_waker(waker);
+        }
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
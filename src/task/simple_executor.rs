@@ -1,48 +1,386 @@
-use super::Task;
-use core::task::RawWakerVTable;
-use core::task::{Waker, RawWaker};
-use alloc::collections::VecDeque;
-use core::task::{Context, Poll};
+//! A single-threaded, deterministic executor for tests.
+//!
+//! Ready tasks are polled in an order drawn from a seeded generator, so a failing schedule
+//! can be replayed by reusing the seed. Time is virtual: tasks sleep on the executor's
+//! `Clock`, which only moves when the executor advances it. `task::timer::sleep` and
+//! `timeout` use that clock too when called from one of its tasks, but `Instant::now`
+//! keeps reading the real clock.
+
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::time::Duration;
+use alloc::task::Wake;
+use crate::sync::IrqSafeMutex;
+
+//the seed `new` uses; any fixed value keeps runs reproducible
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+crate::per_cpu! {
+    //clock of the test executor that is polling a task on this CPU, see `polling_clock`
+    static POLLING_CLOCK: AtomicPtr<Clock> = AtomicPtr::new(ptr::null_mut());
+}
+
+//the clock `timer::sleep` should use instead of the real one, if a test executor is polling
+pub(crate) fn polling_clock() -> Option<Clock> {
+    unsafe { POLLING_CLOCK.get().load(Ordering::Relaxed).as_ref() }.cloned()
+}
 
 pub struct SimpleExecutor {
-    task_queue: VecDeque<Task>,
+    tasks: BTreeMap<TaskId, Task>,
+    abort_wakers: BTreeMap<TaskId, Waker>, //handed to joinable tasks once, apart from any poll
+    ready: Arc<IrqSafeMutex<Vec<TaskId>>>, //woken tasks, in no particular order
+    rng: Rng,
+    clock: Clock,
 }
 
 impl SimpleExecutor {
     pub fn new() -> SimpleExecutor {
+        SimpleExecutor::with_seed(DEFAULT_SEED)
+    }
+
+    /// An executor whose scheduling order is determined by `seed`.
+    pub fn with_seed(seed: u64) -> SimpleExecutor {
         SimpleExecutor {
-            task_queue: VecDeque::new(),
+            tasks: BTreeMap::new(),
+            abort_wakers: BTreeMap::new(),
+            ready: Arc::new(IrqSafeMutex::new(Vec::new())),
+            rng: Rng::new(seed),
+            clock: Clock::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
-        self.task_queue.push_back(task)
+        let task_id = task.id;
+        let abort_waker = Waker::from(Arc::new(TestWaker { task_id, ready: self.ready.clone() }));
+        task.register_abort_waker(&abort_waker);
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.abort_wakers.insert(task_id, abort_waker);
+        self.ready.lock().push(task_id);
     }
 
-    pub fn run(&mut self) {
-        while let Some(mut task) = self.task_queue.pop_front() {
-            let waker = dummy_waker();
+    /// The virtual clock the tasks of this executor should sleep on.
+    pub fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    /// Virtual time since the executor was created.
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Tasks that did not finish yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Polls woken tasks in random order until none is left. Time does not move.
+    ///
+    /// Panics if a task returns `Pending` without having stored its waker anywhere or woken
+    /// itself, as nothing could ever poll it again.
+    pub fn run_until_stalled(&mut self) {
+        let clock = &self.clock as *const Clock as *mut Clock;
+        while let Some(task_id) = self.next_ready() {
+            let task = match self.tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, //finished, but an old waker fired
+            };
+            //a fresh waker for every poll, so that we can tell whether the task kept it
+            let task_waker = Arc::new(TestWaker { task_id, ready: self.ready.clone() });
+            let waker = Waker::from(task_waker.clone());
             let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {} // task done
-                Poll::Pending => self.task_queue.push_back(task),
+            let outer = POLLING_CLOCK.get().swap(clock, Ordering::Relaxed); //executors may be nested
+            let result = task.poll(&mut context);
+            POLLING_CLOCK.get().store(outer, Ordering::Relaxed);
+            drop(waker);
+            match result {
+                Poll::Ready(()) => {
+                    self.tasks.remove(&task_id);
+                    self.abort_wakers.remove(&task_id);
+                }
+                Poll::Pending => {
+                    let woken = self.ready.lock().contains(&task_id);
+                    if Arc::strong_count(&task_waker) == 1 && !woken {
+                        panic!(
+                            "task `{}` returned Pending without registering its waker",
+                            task.name()
+                        );
+                    }
+                }
             }
         }
     }
+
+    /// Moves virtual time forward by `duration`, running the tasks woken on the way at the
+    /// time they were due.
+    pub fn advance(&mut self, duration: Duration) {
+        let target = self.clock.now() + duration;
+        self.run_until_stalled();
+        while let Some(deadline) = self.clock.next_deadline().filter(|&deadline| deadline <= target) {
+            self.clock.set(deadline);
+            self.run_until_stalled();
+        }
+        self.clock.set(target);
+        self.run_until_stalled();
+    }
+
+    /// Runs until every task finished, skipping virtual time ahead whenever all tasks sleep.
+    ///
+    /// Returns early if the remaining tasks wait for something other than the clock, see
+    /// `task_count`.
+    pub fn run(&mut self) {
+        loop {
+            self.run_until_stalled();
+            if self.tasks.is_empty() {
+                return;
+            }
+            match self.clock.next_deadline() {
+                Some(deadline) => self.clock.set(deadline),
+                None => return,
+            }
+        }
+    }
+
+    //removes a random task from the ready list
+    fn next_ready(&mut self) -> Option<TaskId> {
+        let mut ready = self.ready.lock();
+        if ready.is_empty() {
+            return None;
+        }
+        let index = self.rng.below(ready.len());
+        Some(ready.swap_remove(index))
+    }
+}
+
+struct TestWaker {
+    task_id: TaskId,
+    ready: Arc<IrqSafeMutex<Vec<TaskId>>>,
+}
+
+impl Wake for TestWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut ready = self.ready.lock();
+        if !ready.contains(&self.task_id) {
+            ready.push(self.task_id);
+        }
+    }
+}
+
+//xorshift64*, plenty for shuffling a handful of tasks
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(if seed == 0 { DEFAULT_SEED } else { seed }) //zero would stay zero forever
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// Virtual time of a `SimpleExecutor`. Cheap to clone, all clones share the same time.
+#[derive(Clone)]
+pub struct Clock {
+    state: Arc<IrqSafeMutex<ClockState>>,
+}
+
+struct ClockState {
+    now: Duration,
+    next_id: u64,
+    sleepers: Vec<Sleeper>,
+}
+
+struct Sleeper {
+    id: u64,
+    deadline: Duration,
+    waker: Waker,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Clock {
+            state: Arc::new(IrqSafeMutex::new(ClockState {
+                now: Duration::ZERO,
+                next_id: 0,
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        self.state.lock().now
+    }
+
+    /// Completes once the executor advanced the clock by `duration`.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// Completes once the clock reads `deadline`.
+    pub fn sleep_until(&self, deadline: Duration) -> Sleep {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        Sleep { clock: self.clone(), id, deadline }
+    }
+
+    //the earliest deadline still ahead; sleepers that are due but nobody polls again don't count
+    fn next_deadline(&self) -> Option<Duration> {
+        let state = self.state.lock();
+        state
+            .sleepers
+            .iter()
+            .map(|sleeper| sleeper.deadline)
+            .filter(|&deadline| deadline > state.now)
+            .min()
+    }
+
+    //moves the time and wakes the sleepers that are due
+    fn set(&self, now: Duration) {
+        let mut state = self.state.lock();
+        state.now = now;
+        for sleeper in state.sleepers.iter().filter(|sleeper| sleeper.deadline <= now) {
+            sleeper.waker.wake_by_ref();
+        }
+    }
+}
+
+/// A sleep on a `Clock`, see `Clock::sleep`.
+pub struct Sleep {
+    clock: Clock,
+    id: u64,
+    deadline: Duration,
 }
 
+impl Future for Sleep {
+    type Output = ();
 
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.clock.state.lock();
+        if state.now >= self.deadline {
+            state.sleepers.retain(|sleeper| sleeper.id != self.id);
+            return Poll::Ready(());
+        }
+        match state.sleepers.iter_mut().find(|sleeper| sleeper.id == self.id) {
+            Some(sleeper) => sleeper.waker.clone_from(cx.waker()),
+            None => state.sleepers.push(Sleeper {
+                id: self.id,
+                deadline: self.deadline,
+                waker: cx.waker().clone(),
+            }),
+        }
+        Poll::Pending
+    }
+}
 
-fn dummy_raw_waker() -> RawWaker {
-    fn no_op(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        dummy_raw_waker()
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.clock.state.lock().sleepers.retain(|sleeper| sleeper.id != self.id);
     }
+}
 
-    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
-    RawWaker::new(0 as *const (), vtable)
+#[test_case]
+fn test_virtual_sleep_skips_ahead() {
+    let mut executor = SimpleExecutor::new();
+    let clock = executor.clock();
+    let woke_at = Arc::new(IrqSafeMutex::new(None));
+    let task_woke_at = woke_at.clone();
+    executor.spawn(Task::new(async move {
+        clock.sleep(Duration::from_secs(3600)).await;
+        *task_woke_at.lock() = Some(clock.now());
+    }));
+    executor.run();
+    assert_eq!(*woke_at.lock(), Some(Duration::from_secs(3600)));
 }
 
-fn dummy_waker() -> Waker {
-    unsafe { Waker::from_raw(dummy_raw_waker()) }
-}
\ No newline at end of file
+//kernel code sleeping through `task::timer` gets virtual time as well
+#[test_case]
+fn test_timer_sleep_uses_virtual_time() {
+    let mut executor = SimpleExecutor::new();
+    let clock = executor.clock();
+    let start = crate::time::Instant::now();
+    executor.spawn(Task::new(async {
+        super::timer::sleep(Duration::from_secs(3600)).await;
+        let slow = super::timer::sleep(Duration::from_secs(7200));
+        assert!(super::timer::timeout(Duration::from_secs(60), slow).await.is_err());
+    }));
+    executor.run();
+    assert_eq!(executor.task_count(), 0);
+    assert_eq!(clock.now(), Duration::from_secs(3660));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+//a sleep that is polled once and then held without being polled again must not stop `run`
+#[test_case]
+fn test_run_returns_with_unpolled_expired_sleep() {
+    let mut executor = SimpleExecutor::new();
+    let clock = executor.clock();
+    let task_clock = clock.clone();
+    executor.spawn(Task::new(async move {
+        let mut sleep = task_clock.sleep(Duration::from_millis(1));
+        //registered once, then left alone while the task waits for something else
+        core::future::poll_fn(|cx| {
+            let _ = Pin::new(&mut sleep).poll(cx);
+            Poll::Ready(())
+        })
+        .await;
+        task_clock.sleep(Duration::from_millis(5)).await;
+    }));
+    executor.run();
+    assert_eq!(executor.task_count(), 0);
+    assert_eq!(clock.now(), Duration::from_millis(5));
+}
+
+#[test_case]
+fn test_advance_runs_sleepers_at_their_deadline() {
+    let mut executor = SimpleExecutor::new();
+    let clock = executor.clock();
+    let log = Arc::new(IrqSafeMutex::new(Vec::new()));
+    for &millis in &[30, 10, 20] {
+        let (clock, log) = (clock.clone(), log.clone());
+        executor.spawn(Task::new(async move {
+            clock.sleep(Duration::from_millis(millis)).await;
+            log.lock().push(clock.now().as_millis());
+        }));
+    }
+    executor.advance(Duration::from_millis(25));
+    assert_eq!(*log.lock(), [10, 20]);
+    assert_eq!(executor.task_count(), 1);
+    executor.advance(Duration::from_millis(5));
+    assert_eq!(*log.lock(), [10, 20, 30]);
+}
+
+//the same seed gives the same schedule, and some other seed gives a different one
+#[test_case]
+fn test_seed_determines_order() {
+    fn schedule(seed: u64) -> Vec<usize> {
+        let mut executor = SimpleExecutor::with_seed(seed);
+        let order = Arc::new(IrqSafeMutex::new(Vec::new()));
+        for i in 0..8 {
+            let order = order.clone();
+            executor.spawn(Task::new(async move { order.lock().push(i) }));
+        }
+        executor.run();
+        let order = order.lock();
+        order.clone()
+    }
+    assert_eq!(schedule(1), schedule(1));
+    assert!((2..10).any(|seed| schedule(seed) != schedule(1)));
+}
@@ -0,0 +1,333 @@
+//! Async synchronization between tasks. Waiting tasks are woken in the order they started
+//! waiting. Releasing and notifying never allocate, so interrupt handlers can do it too.
+
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use crate::sync::IrqSafeMutex;
+
+//tasks waiting on a primitive, oldest first; a future keeps its id to find its entry again
+struct WaitList {
+    next_id: u64,
+    entries: VecDeque<Entry>,
+}
+
+struct Entry {
+    id: u64,
+    waker: Waker,
+    notified: Option<Notification>, //only used by Notify
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl WaitList {
+    const fn new() -> Self {
+        WaitList { next_id: 0, entries: VecDeque::new() }
+    }
+
+    //adds the waiter or refreshes its waker, returns its id
+    fn register(&mut self, id: Option<u64>, waker: &Waker) -> u64 {
+        if let Some(id) = id {
+            if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+                if !entry.waker.will_wake(waker) {
+                    entry.waker = waker.clone();
+                }
+                return id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push_back(Entry { id, waker: waker.clone(), notified: None });
+        id
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        self.entries.remove(index)
+    }
+
+    fn is_first(&self, id: Option<u64>) -> bool {
+        match self.entries.front() {
+            None => true,
+            Some(entry) => Some(entry.id) == id,
+        }
+    }
+
+    fn wake_first(&self) {
+        if let Some(entry) = self.entries.front() {
+            entry.waker.wake_by_ref();
+        }
+    }
+}
+
+/// A counting semaphore.
+pub struct Semaphore {
+    state: IrqSafeMutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitList,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { state: IrqSafeMutex::new(SemaphoreState { permits, waiters: WaitList::new() }) }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Takes a permit if one is free and nobody is waiting for one.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock();
+        if state.permits > 0 && state.waiters.entries.is_empty() {
+            state.permits -= 1;
+            Some(Permit { semaphore: self })
+        } else {
+            None
+        }
+    }
+
+    /// Waits for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire { semaphore: self, id: None }
+    }
+
+    /// Returns `n` permits and wakes the first waiter. Usable from interrupt handlers.
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.waiters.wake_first();
+    }
+}
+
+/// A permit taken from a [`Semaphore`], given back when dropped.
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Permit<'_> {
+    /// Drops the permit without giving it back.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    id: Option<u64>, //our entry in the wait list, once we had to wait
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Permit<'a>> {
+        let semaphore = self.semaphore;
+        let mut state = semaphore.state.lock();
+        if state.permits > 0 && state.waiters.is_first(self.id) {
+            state.permits -= 1;
+            if let Some(id) = self.id.take() {
+                state.waiters.remove(id);
+            }
+            if state.permits > 0 {
+                state.waiters.wake_first(); //there is enough for the next one too
+            }
+            return Poll::Ready(Permit { semaphore });
+        }
+        self.id = Some(state.waiters.register(self.id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock();
+            state.waiters.remove(id);
+            if state.permits > 0 {
+                state.waiters.wake_first(); //we may have been woken for a permit we no longer take
+            }
+        }
+    }
+}
+
+/// A mutex whose `lock` waits asynchronously instead of spinning, so the guard may be held
+/// across `.await`s. Not for interrupt handlers, use [`crate::sync::IrqSafeMutex`] there.
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {} //access to the value is serialized by the semaphore
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { semaphore: Semaphore::new(1), value: UnsafeCell::new(value) }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        Some(MutexGuard { mutex: self, _permit: permit })
+    }
+
+    /// Waits until the lock is free.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock { mutex: self, acquire: self.semaphore.acquire() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    acquire: Acquire<'a>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mutex = self.mutex;
+        Pin::new(&mut self.acquire).poll(cx).map(|permit| MutexGuard { mutex, _permit: permit })
+    }
+}
+
+/// Unlocks the mutex when dropped.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _permit: Permit<'a>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+/// Wakes waiting tasks without passing any data.
+///
+/// A `notify_one` with nobody waiting is remembered, so the next `notified` completes
+/// right away; this avoids missing a notification sent just before a task starts waiting.
+pub struct Notify {
+    state: IrqSafeMutex<NotifyState>,
+}
+
+struct NotifyState {
+    stored: bool, //a notify_one nobody has consumed yet
+    waiters: WaitList,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify { state: IrqSafeMutex::new(NotifyState { stored: false, waiters: WaitList::new() }) }
+    }
+
+    /// Wakes the longest waiting task, or the next one to wait. Usable from interrupt handlers.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        Self::notify_one_locked(&mut state);
+    }
+
+    fn notify_one_locked(state: &mut NotifyState) {
+        match state.waiters.entries.iter_mut().find(|entry| entry.notified.is_none()) {
+            Some(entry) => {
+                entry.notified = Some(Notification::One);
+                entry.waker.wake_by_ref();
+            }
+            None => state.stored = true,
+        }
+    }
+
+    /// Wakes every task waiting right now. Usable from interrupt handlers.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for entry in state.waiters.entries.iter_mut().filter(|entry| entry.notified.is_none()) {
+            entry.notified = Some(Notification::All);
+            entry.waker.wake_by_ref();
+        }
+    }
+
+    /// Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, id: None }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+        match self.id {
+            None if state.stored => {
+                state.stored = false;
+                return Poll::Ready(());
+            }
+            Some(id) => {
+                let notified = state.waiters.entries.iter().any(|entry| entry.id == id && entry.notified.is_some());
+                if notified {
+                    state.waiters.remove(id);
+                    self.id = None;
+                    return Poll::Ready(());
+                }
+            }
+            None => {}
+        }
+        self.id = Some(state.waiters.register(self.id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.notify.state.lock();
+            if let Some(entry) = state.waiters.remove(id) {
+                if entry.notified == Some(Notification::One) {
+                    Notify::notify_one_locked(&mut state); //hand it on instead of losing it
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use crate::interrupts::{irq::{self, IrqReturn}, InterruptIndex};
+use crate::sync::IrqSafeMutex;
+use crate::time::Instant;
+use super::simple_executor;
+
+//pending sleeps; the timer interrupt only wakes entries, removing them (and dropping the
+//waker) is left to the future itself so the interrupt handler never frees memory
+static SLEEPERS: IrqSafeMutex<Vec<Sleeper>> = IrqSafeMutex::new(Vec::new());
+
+struct Sleeper {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+/// Hooks the sleep queue up to the timer interrupt. The timer line is shared with other handlers.
+pub fn init() {
+    irq::register(InterruptIndex::Timer.irq(), wake_expired)
+        .expect("failed to register sleep timer");
+}
+
+fn wake_expired(_irq: u8) -> IrqReturn {
+    wake_expired_sleepers();
+    IrqReturn::NotHandled //just listening in, the line's real handler acknowledges the tick
+}
+
+//wakes every sleeper whose deadline passed and returns the earliest deadline still pending
+pub(crate) fn wake_expired_sleepers() -> Option<Instant> {
+    let now = Instant::now();
+    let mut next = None;
+    for sleeper in SLEEPERS.lock().iter() {
+        if sleeper.deadline <= now {
+            sleeper.waker.wake_by_ref();
+        } else if next.map_or(true, |next| sleeper.deadline < next) {
+            next = Some(sleeper.deadline);
+        }
+    }
+    next
+}
+
+/// A future that completes once its deadline passed. The resolution is the timer interrupt rate,
+/// or much finer once `hpet::enable_one_shot_interrupts` ran.
+///
+/// Sleeps created while a `SimpleExecutor` polls a task run on its virtual clock instead.
+pub struct Sleep {
+    id: u64,
+    deadline: Instant,
+    virtual_sleep: Option<simple_executor::Sleep>,
+}
+
+/// Completes after `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    let virtual_sleep = simple_executor::polling_clock().map(|clock| clock.sleep(duration));
+    Sleep::new(Instant::now() + duration, virtual_sleep)
+}
+
+/// Completes at `deadline`. On a virtual clock, `deadline` is taken relative to the real time now.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    let virtual_sleep = simple_executor::polling_clock()
+        .map(|clock| clock.sleep(deadline.duration_since(Instant::now())));
+    Sleep::new(deadline, virtual_sleep)
+}
+
+impl Sleep {
+    fn new(deadline: Instant, virtual_sleep: Option<simple_executor::Sleep>) -> Sleep {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            deadline,
+            virtual_sleep,
+        }
+    }
+
+    fn remove(&self) {
+        SLEEPERS.lock().retain(|s| s.id != self.id);
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if let Some(virtual_sleep) = &mut self.get_mut().virtual_sleep {
+            return Pin::new(virtual_sleep).poll(cx);
+        }
+        if Instant::now() >= self.deadline {
+            self.remove();
+            return Poll::Ready(());
+        }
+
+        let mut sleepers = SLEEPERS.lock();
+        match sleepers.iter_mut().find(|s| s.id == self.id) {
+            Some(sleeper) => sleeper.waker.clone_from(cx.waker()),
+            None => sleepers.push(Sleeper {
+                id: self.id,
+                deadline: self.deadline,
+                waker: cx.waker().clone(),
+            }),
+        }
+        drop(sleepers);
+        crate::hpet::schedule_wakeup(self.deadline); //finer than the timer tick if the HPET is set up
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Error returned by `timeout` when the deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future`, giving up with `Elapsed` if it did not finish within `duration`.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    use futures_util::future::{select, Either};
+    use futures_util::pin_mut;
+
+    let deadline = sleep(duration);
+    pin_mut!(future);
+    pin_mut!(deadline);
+    match select(future, deadline).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
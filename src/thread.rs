@@ -0,0 +1,301 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::fpu::{self, FpuState};
+use crate::process::{self, Pid};
+use crate::{memory, percpu};
+use crate::sync::IrqSafeMutex;
+use crate::time::Instant;
+
+/// Size of the kernel stack each thread gets, below an unmapped guard page.
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// Number of timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1); //0 is the thread the kernel booted on
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: String,
+    rsp: u64, //saved stack pointer while the thread is switched out
+    stack: Option<VirtAddr>, //top of the stack; None for the boot thread, which keeps running on the bootloader's stack
+    kernel_stack_top: VirtAddr, //where ring 3 enters the kernel while this thread runs
+    page_table: PhysFrame, //level 4 table the thread runs on, saved while it is switched out
+    process: Option<Pid>, //the process whose user code the thread runs, if any
+    fpu: Option<Box<FpuState>>,
+    entry: Option<Box<dyn FnOnce() + Send>>, //taken when the thread first runs
+    wake_at: Option<Instant>, //set while the thread sleeps, it is not ready before then
+    finished: bool,
+}
+
+impl Thread {
+    //wraps the code that was running before the first switch
+    fn boot() -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId(0),
+            name: String::from("boot"),
+            rsp: 0,
+            stack: None,
+            kernel_stack_top: percpu::kernel_stack(),
+            page_table: Cr3::read().0,
+            process: process::current(),
+            fpu: None, //the kernel is built without SSE, see `fpu::init`
+            entry: None,
+            wake_at: None,
+            finished: false,
+        })
+    }
+
+    fn fpu_state(&mut self) -> *mut FpuState {
+        self.fpu.as_deref_mut().map_or(ptr::null_mut(), |state| state as *mut FpuState)
+    }
+}
+
+//Threads are scheduled on the bootstrap processor, the only one receiving the PIC timer.
+static CURRENT: IrqSafeMutex<Option<Box<Thread>>> = IrqSafeMutex::new(None);
+static READY: IrqSafeMutex<VecDeque<Box<Thread>>> = IrqSafeMutex::new(VecDeque::new());
+//threads waiting for their `wake_at`, moved back to READY by the timer
+static SLEEPING: IrqSafeMutex<Vec<Box<Thread>>> = IrqSafeMutex::new(Vec::new());
+//finished threads, freed by the next thread to run since their stack is in use until the switch
+static DEAD: IrqSafeMutex<Vec<Box<Thread>>> = IrqSafeMutex::new(Vec::new());
+//tops of the stacks of freed threads; kernel stacks are never unmapped, so they are reused
+static FREE_STACKS: IrqSafeMutex<Vec<VirtAddr>> = IrqSafeMutex::new(Vec::new());
+
+static TICKS_LEFT: AtomicU64 = AtomicU64::new(TIME_SLICE_TICKS);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+//saves the callee-saved registers on the current stack, stores the stack pointer to [rdi], then
+//continues on the stack in rsi: popping that thread's registers and returning into its switch call
+global_asm!(
+    r#"
+    .global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+    "#
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Starts `f` on a new kernel thread; it first runs when the scheduler picks it.
+///
+/// The stack of a finished thread is reused if there is one, otherwise a new one is mapped.
+pub fn spawn<F>(
+    name: &str,
+    f: F,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<ThreadId, MapToError<Size4KiB>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = match FREE_STACKS.lock().pop() {
+        Some(stack) => stack,
+        None => memory::alloc_stack((STACK_SIZE / 4096) as u64, mapper, frame_allocator)?,
+    };
+    let top = stack.as_u64();
+
+    //the frame switch_context expects: six zeroed registers, then the address it returns to.
+    //Above that a null return address for `thread_start`, keeping the ABI's stack alignment.
+    let frame: [u64; 8] = [0, 0, 0, 0, 0, 0, thread_start as usize as u64, 0];
+    let rsp = top - 8 * frame.len() as u64;
+    unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+
+    let id = ThreadId::new();
+    READY.lock().push_back(Box::new(Thread {
+        id,
+        name: String::from(name),
+        rsp,
+        stack: Some(stack),
+        kernel_stack_top: stack,
+        page_table: memory::kernel_level_4_frame(),
+        process: None,
+        fpu: Some(Box::new(FpuState::new())),
+        entry: Some(Box::new(f)),
+        wake_at: None,
+        finished: false,
+    }));
+    Ok(id)
+}
+
+//first code a new thread runs, entered with interrupts disabled from the middle of `schedule`
+extern "C" fn thread_start() -> ! {
+    reap();
+    let entry = CURRENT.lock().as_mut().and_then(|thread| thread.entry.take());
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// The thread running on the calling CPU.
+pub fn current() -> ThreadId {
+    CURRENT.lock().as_ref().map_or(ThreadId(0), |thread| thread.id)
+}
+
+/// Name of the running thread.
+pub fn current_name() -> String {
+    CURRENT.lock().as_ref().map_or_else(|| String::from("boot"), |thread| thread.name.clone())
+}
+
+/// Gives up the rest of the time slice.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Blocks the calling thread until `deadline`, running other threads meanwhile.
+///
+/// The thread is woken by the first timer tick after the deadline.
+pub fn sleep_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        interrupts::without_interrupts(|| {
+            CURRENT.lock().get_or_insert_with(Thread::boot).wake_at = Some(deadline);
+            schedule(); //returns at once if nothing else is ready
+        });
+        if Instant::now() < deadline {
+            interrupts::enable_and_hlt(); //still on the CPU, a tick may switch away from us here
+        }
+    }
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = CURRENT.lock().as_mut() {
+            thread.wake_at = None;
+        }
+    });
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        let thread = current.get_or_insert_with(Thread::boot);
+        thread.finished = true;
+    });
+    loop {
+        interrupts::without_interrupts(schedule); //returns while nothing else is ready
+        interrupts::enable_and_hlt();
+    }
+}
+
+//called from the timer interrupt: counts down the time slice of the running thread
+pub(crate) fn tick() {
+    if TICKS_LEFT.fetch_sub(1, Ordering::Relaxed) <= 1 {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+    wake_sleepers();
+}
+
+//moves the threads whose deadline passed back to the ready queue
+fn wake_sleepers() {
+    let now = Instant::now();
+    let mut ready = READY.lock(); //same lock order as `schedule`
+    let mut sleeping = SLEEPING.lock();
+    let mut index = 0;
+    while index < sleeping.len() {
+        if sleeping[index].wake_at.map_or(true, |deadline| deadline <= now) {
+            let mut thread = sleeping.swap_remove(index);
+            thread.wake_at = None;
+            ready.push_back(thread);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+//called by the IRQ dispatcher after the EOI was sent; switching any earlier would leave the
+//line in service while the next thread runs
+pub(crate) fn preempt_if_needed() {
+    if NEED_RESCHED.load(Ordering::Relaxed) && percpu::cpu_id() == 0 {
+        schedule();
+    }
+}
+
+//switches to the next ready thread, if there is one; must be called with interrupts disabled
+fn schedule() {
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    TICKS_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+
+    let (old_rsp, new_rsp) = {
+        let mut ready = READY.lock();
+        let mut next = match ready.pop_front() {
+            Some(next) => next,
+            None => return, //keep running the current thread
+        };
+        let mut current = CURRENT.lock();
+        let mut previous = current.take().unwrap_or_else(Thread::boot);
+
+        fpu::switch_to(next.fpu_state());
+        percpu::set_kernel_stack(next.kernel_stack_top);
+        //threads blocked in a system call keep their process and its page tables
+        let (page_table, cr3_flags) = Cr3::read();
+        previous.page_table = page_table;
+        previous.process = process::current();
+        if next.page_table != page_table {
+            unsafe { Cr3::write(next.page_table, cr3_flags) };
+        }
+        process::set_current(next.process);
+
+        //the boxes keep the threads at the same address, so the pointer stays valid after the move
+        let old_rsp = &mut previous.rsp as *mut u64;
+        let new_rsp = next.rsp;
+        if previous.finished {
+            DEAD.lock().push(previous);
+        } else if previous.wake_at.is_some() {
+            SLEEPING.lock().push(previous);
+        } else {
+            ready.push_back(previous);
+        }
+        *current = Some(next);
+        (old_rsp, new_rsp)
+    };
+    unsafe { switch_context(old_rsp, new_rsp) };
+
+    //running again, possibly after a long time
+    reap();
+}
+
+fn reap() {
+    let dead = core::mem::take(&mut *DEAD.lock());
+    FREE_STACKS.lock().extend(dead.iter().filter_map(|thread| thread.stack));
+}
+
+/// Number of threads waiting for the CPU.
+pub fn ready_count() -> usize {
+    READY.lock().len()
+}
@@ -0,0 +1,217 @@
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+//the PIT's input clock, fixed on every PC
+const PIT_FREQUENCY: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+
+//the clock is `BASE_NANOS` at TSC value `BASE_TSC` and advances at `TSC_FREQUENCY` from there.
+//All three change together when the frequency is replaced, under a sequence counter that is odd
+//while they are being written, so readers retry instead of mixing old and new values.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0); //ticks per second, 0 until calibrated
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Calibrates the TSC against the PIT and starts the monotonic clock.
+pub fn init() {
+    if !crate::cpu::features().invariant_tsc {
+        crate::println!("WARNING: TSC is not invariant, time may drift with CPU frequency changes");
+    }
+    let frequency = x86_64::instructions::interrupts::without_interrupts(calibrate_with_pit);
+    set_tsc_frequency(frequency); //the clock reads 0 until now, so this is Instant 0
+}
+
+/// Replaces the TSC frequency, e.g. with a more precise calibration against another clock.
+///
+/// Only time from now on is measured at the new frequency, so instants taken earlier and
+/// deadlines based on them stay where they are and the clock keeps moving forward.
+pub fn set_tsc_frequency(frequency: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let tsc = rdtsc();
+        let now = nanos_at(tsc);
+        SEQUENCE.fetch_add(1, Ordering::AcqRel);
+        BASE_TSC.store(tsc, Ordering::Relaxed);
+        BASE_NANOS.store(now, Ordering::Relaxed);
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        SEQUENCE.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// Ticks per second of the TSC as determined at boot.
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+//counts down a known interval on PIT channel 2 (the speaker channel, which has no interrupt
+//attached) and measures how far the TSC moved in the meantime
+fn calibrate_with_pit() -> u64 {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    let mut speaker: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        let control = speaker.read();
+        speaker.write((control & !0x02) | 0x01); //enable the channel 2 gate, keep the speaker off
+
+        command.write(0b1011_0000); //channel 2, low byte then high byte, mode 0 (interrupt on terminal count)
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8); //counting starts now
+
+        let start = rdtsc();
+        while speaker.read() & 0x20 == 0 {} //the channel 2 output goes high when the count reaches zero
+        let end = rdtsc();
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+//clock value at the given TSC reading
+fn nanos_at(tsc: u64) -> u64 {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence % 2 == 1 {
+            core::hint::spin_loop(); //an update is in progress on another CPU
+            continue;
+        }
+        let base_tsc = BASE_TSC.load(Ordering::Acquire);
+        let base_nanos = BASE_NANOS.load(Ordering::Acquire);
+        let frequency = TSC_FREQUENCY.load(Ordering::Acquire);
+        if SEQUENCE.load(Ordering::Acquire) != sequence {
+            continue;
+        }
+        if frequency == 0 {
+            return base_nanos;
+        }
+        let ticks = tsc.saturating_sub(base_tsc);
+        return base_nanos + (u128::from(ticks) * u128::from(NANOS_PER_SEC) / u128::from(frequency)) as u64;
+    }
+}
+
+/// A point on the monotonic clock, with nanosecond resolution, counted from boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(nanos_at(rdtsc()))
+    }
+
+    /// Time since boot.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// Time passed from `earlier` to `self`, zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time passed since this instant was taken.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A free running counter that can be read as nanoseconds.
+pub trait ClockSource {
+    fn name(&self) -> &'static str;
+
+    /// Nanoseconds since some fixed, source specific point.
+    fn nanos(&self) -> u64;
+}
+
+/// The calibrated TSC, the clock behind `Instant`.
+pub struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn nanos(&self) -> u64 {
+        Instant::now().0
+    }
+}
+
+/// Spins until `duration` has passed. Only for short waits, e.g. while talking to hardware.
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+
+//a forward moving clock and a sub-millisecond busy wait
+#[test_case]
+fn test_instant_monotonic() {
+    let start = Instant::now();
+    busy_wait(Duration::from_micros(100));
+    let end = Instant::now();
+    assert!(end > start);
+    assert!(end - start >= Duration::from_micros(100));
+}
+
+//recalibrating must neither move existing instants nor make the clock jump backwards
+#[test_case]
+fn test_frequency_change_keeps_instants() {
+    let frequency = tsc_frequency();
+    let before = Instant::now();
+    set_tsc_frequency(frequency * 2); //time would halve if it were rescaled from boot
+    let after = Instant::now();
+    set_tsc_frequency(frequency);
+    assert!(after >= before);
+    assert!(Instant::now() >= after);
+}
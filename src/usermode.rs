@@ -0,0 +1,48 @@
+use core::arch::asm;
+use x86_64::VirtAddr;
+use crate::gdt;
+
+/// Start of the part of the address space reserved for user programs, far from every kernel mapping
+/// so that none of its page tables are shared with (non user accessible) kernel tables.
+pub const USER_REGION_START: u64 = 0x0000_2000_0000_0000;
+/// End (exclusive) of the user region.
+pub const USER_REGION_END: u64 = 0x0000_3000_0000_0000;
+
+const USER_RFLAGS: u64 = 0x202; //interrupts enabled, bit 1 is reserved and always set
+
+/// Drops to ring 3 and starts executing at `entry` with the given stack pointer.
+///
+/// The code and stack must be mapped `USER_ACCESSIBLE`. The only ways back into the kernel are
+/// interrupts, exceptions and system calls, which switch to the TSS privilege stack.
+/// Swaps in the user GS base on the way out, which the entry paths swap back, see `percpu::init`.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code_selector = u64::from(selectors.user_code_selector.0);
+    let data_selector = u64::from(selectors.user_data_selector.0);
+
+    //build the frame iretq expects: ss, rsp, rflags, cs, rip
+    //interrupts stay off until iretq, nothing may run in the kernel with the user GS base
+    asm!(
+        "cli",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        "swapgs",
+        "iretq",
+        data = in(reg) data_selector,
+        stack = in(reg) stack.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        code = in(reg) code_selector,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
+
+/// Whether an interrupt stack frame's code segment says the interrupted code ran in ring 3.
+pub fn from_user_mode(code_segment: u64) -> bool {
+    code_segment & 0b11 == 3
+}
@@ -1,7 +1,8 @@
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 
 #[allow(dead_code)]
@@ -134,8 +135,8 @@ impl fmt::Write for Writer {
 
 
 lazy_static! { //since one-time initialization of statics with non-const functions is a common problem in Rust hence use lazy_static
-    //spinning mutex to add safe interior mutability to static WRITER
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    //spinning mutex to add safe interior mutability to static WRITER, interrupts stay off while it is held
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -158,11 +159,53 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts; //import x86_64 interrupt utilities
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();  //no interrupt can occur as long as the Mutex is locked
-    });
+    WRITER.lock().write_fmt(args).unwrap();  //no interrupt can occur as long as the Mutex is locked
+}
+
+
+//like print!/println! but safe to use while WRITER or SERIAL1 might be locked by the code we interrupted,
+//the output goes to both the screen and the serial port
+#[macro_export]
+macro_rules! fatal_print {
+    ($($arg:tt)*) => ($crate::vga_buffer::_fatal_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! fatal_println {
+    () => ($crate::fatal_print!("\n"));
+    ($($arg:tt)*) => ($crate::fatal_print!("{}\n", format_args!($($arg)*)));
+}
+
+const EMERGENCY_LOCK_ATTEMPTS: usize = 10_000; //enough for another CPU to finish a line, far from a hang
+
+static EMERGENCY_COLUMN: AtomicUsize = AtomicUsize::new(0); //cursor of the emergency writer between calls
+
+#[doc(hidden)]
+pub fn _fatal_print(args: fmt::Arguments) {
+    _emergency_print(args);
+    crate::serial::_emergency_print(args);
+}
+
+//prints through WRITER if it can be locked, otherwise writes to the VGA buffer directly
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(mut writer) = WRITER.try_lock_for(EMERGENCY_LOCK_ATTEMPTS) {
+        let _ = writer.write_fmt(args);
+        return;
+    }
+
+    //the holder is most likely the code this handler interrupted, which will never run again or does not
+    //care about a garbled line, so alias the buffer instead of waiting for it
+    let mut writer = Writer {
+        column_position: EMERGENCY_COLUMN.load(Ordering::Relaxed),
+        color_code: ColorCode::new(Color::LightRed, Color::Black),
+        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    };
+    let _ = writer.write_fmt(args);
+    EMERGENCY_COLUMN.store(writer.column_position, Ordering::Relaxed);
 }
 
 
@@ -186,15 +229,12 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| { //disable interrupts for the test’s duration to avoid deadlock
-        let mut writer = WRITER.lock(); //to keep WRITER locked for the complete duration of the test
-        writeln!(writer, "\n{}", s).expect("writeln failed"); //use the writeln macro that allows printing to an already locked writer
-        for (i, c) in s.chars().enumerate() { //iterates over the screen characters of the static WRITER
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    let mut writer = WRITER.lock(); //to keep WRITER locked for the complete duration of the test, the guard also keeps interrupts disabled
+    writeln!(writer, "\n{}", s).expect("writeln failed"); //use the writeln macro that allows printing to an already locked writer
+    for (i, c) in s.chars().enumerate() { //iterates over the screen characters of the static WRITER
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,185 @@
+//run using 'cargo test --test async_sync'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::future::{poll_fn, Future};
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use rust_os::interrupts::{irq::{self, IrqReturn}, InterruptIndex};
+use rust_os::task::channel::{broadcast, mpsc, oneshot};
+use rust_os::task::executor::{Executor, Spawner};
+use rust_os::task::sync::{Mutex, Notify, Semaphore};
+use rust_os::task::timer;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+static TICKS: OnceCell<mpsc::Sender<u32>> = OnceCell::uninit();
+static SENT_TICKS: AtomicU32 = AtomicU32::new(0);
+static TICK_NOTIFY: Notify = Notify::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    SPAWNER.try_init_once(|| executor.spawner()).expect("spawner already set");
+    executor.spawn(run_tests());
+    executor.run();
+}
+
+//the executor never returns, so the last check ends the test from inside a task
+async fn run_tests() {
+    let spawner = SPAWNER.get().unwrap().clone();
+
+    serial_print!("async_sync::mpsc...\t");
+    let (tx, mut rx) = mpsc::channel(2);
+    let producer = spawner.spawn(async move {
+        for i in 0..10 {
+            tx.send(i).await.expect("receiver dropped");
+        }
+    });
+    let mut received = Vec::new();
+    while let Some(value) = rx.recv().await {
+        received.push(value);
+    }
+    assert_eq!(received, (0..10).collect::<Vec<_>>());
+    assert_eq!(producer.await, Ok(()));
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::mpsc_closed...\t");
+    let (tx, rx) = mpsc::channel(1);
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    drop(rx);
+    assert!(tx.is_closed());
+    assert_eq!(tx.send(3).await, Err(mpsc::SendError(3)));
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::mpsc_cancelled_send...\t");
+    let (tx, mut rx) = mpsc::channel(1);
+    assert_eq!(tx.try_send(0), Ok(()));
+    let second_tx = tx.clone();
+    let mut first = tx.send(1);
+    poll_fn(|cx| {
+        assert!(Pin::new(&mut first).poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+    let second = spawner.spawn(async move { second_tx.send(2).await });
+    timer::sleep(Duration::from_millis(5)).await; //let the second sender queue up behind the first
+    assert_eq!(rx.try_recv(), Ok(0)); //wakes the first sender only
+    drop(first); //which must pass the free slot on
+    let sent = timer::timeout(Duration::from_millis(100), second).await;
+    assert_eq!(sent, Ok(Ok(Ok(()))), "second sender was never woken");
+    assert_eq!(rx.try_recv(), Ok(2));
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::oneshot...\t");
+    let (tx, rx) = oneshot::channel();
+    spawner.spawn(async move { tx.send("done").unwrap() }).detach();
+    assert_eq!(rx.await, Ok("done"));
+    let (tx, rx) = oneshot::channel::<u32>();
+    drop(tx);
+    assert_eq!(rx.await, Err(oneshot::RecvError));
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::broadcast...\t");
+    let (tx, mut first) = broadcast::channel(2);
+    let mut second = tx.subscribe();
+    assert_eq!(tx.send(1), Ok(2));
+    assert_eq!(first.recv().await, Ok(1));
+    assert_eq!(tx.send(2), Ok(2));
+    assert_eq!(tx.send(3), Ok(2));
+    assert_eq!(second.recv().await, Err(broadcast::RecvError::Lagged(1))); //1 was overwritten
+    assert_eq!(second.recv().await, Ok(2));
+    assert_eq!(first.recv().await, Ok(2));
+    assert_eq!(first.recv().await, Ok(3));
+    drop(tx);
+    assert_eq!(first.recv().await, Err(broadcast::RecvError::Closed));
+    assert_eq!(second.recv().await, Ok(3));
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::mutex...\t");
+    let counter = Arc::new(Mutex::new(0));
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            spawner.spawn(async move {
+                let mut value = counter.lock().await;
+                let seen = *value;
+                timer::sleep(Duration::from_millis(1)).await; //hold the lock across an await
+                *value = seen + 1;
+            })
+        })
+        .collect();
+    for worker in workers {
+        assert_eq!(worker.await, Ok(()));
+    }
+    assert_eq!(*counter.lock().await, 4);
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::semaphore...\t");
+    let semaphore = Semaphore::new(2);
+    let a = semaphore.acquire().await;
+    let b = semaphore.try_acquire().expect("second permit missing");
+    assert!(semaphore.try_acquire().is_none());
+    drop(a);
+    assert_eq!(semaphore.available_permits(), 1);
+    b.forget();
+    let _c = semaphore.acquire().await;
+    assert_eq!(semaphore.available_permits(), 0);
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::notify...\t");
+    let notify = Notify::new();
+    notify.notify_one(); //stored for the next waiter
+    notify.notified().await;
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::from_interrupt...\t");
+    let (tx, mut ticks) = mpsc::channel(4);
+    TICKS.try_init_once(|| tx).expect("tick sender already set");
+    irq::register(InterruptIndex::Timer.irq(), send_tick).expect("failed to register timer handler");
+    for expected in 0..3 {
+        assert_eq!(ticks.recv().await, Some(expected));
+    }
+    TICK_NOTIFY.notified().await; //notified with the last tick, kept even if we were not waiting yet
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+//sends the first three timer ticks into the channel, then goes quiet
+fn send_tick(_irq: u8) -> IrqReturn {
+    let tick = SENT_TICKS.load(Ordering::SeqCst);
+    if tick < 3 && TICKS.get().unwrap().try_send(tick).is_ok() {
+        SENT_TICKS.store(tick + 1, Ordering::SeqCst);
+        TICK_NOTIFY.notify_one();
+    }
+    IrqReturn::NotHandled
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,185 @@
+//run using 'cargo test --test elf_loader'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::convert::TryInto;
+use core::panic::PanicInfo;
+use rust_os::elf::{self, ElfError};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::paging::{
+    mapper::TranslateResult, FrameAllocator, FrameDeallocator, PageTableFlags, Translate,
+};
+use x86_64::VirtAddr;
+
+//see user_programs/hello.s for the source and the linker invocation
+static HELLO: &[u8] = include_bytes!("user_programs/hello.elf");
+
+const ENTRY: u64 = 0x2000_0040_1000;
+const DATA: u64 = 0x2000_0060_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    loads_segments(&mut frame_allocator);
+    builds_initial_stack(&mut frame_allocator);
+    rejects_bad_magic(&mut frame_allocator);
+    frees_address_space_on_error(&mut frame_allocator);
+    rejects_oversized_arguments(&mut frame_allocator);
+    rejects_entry_outside_code(&mut frame_allocator);
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+fn flags_at(mapper: &impl Translate, addr: u64) -> PageTableFlags {
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} is not mapped", addr),
+    }
+}
+
+//reads a u64 from an address space that is not active
+fn read_u64(mapper: &impl Translate, addr: u64) -> u64 {
+    let phys = mapper.translate_addr(VirtAddr::new(addr)).expect("address not mapped");
+    unsafe { memory::phys_to_virt(phys).as_ptr::<u64>().read_unaligned() }
+}
+
+fn loads_segments(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::loads_segments...\t");
+
+    let mut program = elf::load(HELLO, &[], &[], frame_allocator).expect("loading failed");
+    assert_eq!(program.entry.as_u64(), ENTRY);
+    let mapper = program.address_space.mapper();
+
+    let text = flags_at(&mapper, ENTRY);
+    assert!(text.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+
+    let data = flags_at(&mapper, DATA);
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert_eq!(read_u64(&mapper, DATA), 0x1122_3344_5566_7788);
+
+    //.bss follows .data and spills onto the next page
+    assert_eq!(read_u64(&mapper, DATA + 8), 0);
+    assert_eq!(read_u64(&mapper, DATA + 0x2000), 0);
+
+    serial_println!("[ok]");
+}
+
+fn builds_initial_stack(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::builds_initial_stack...\t");
+
+    let mut program = elf::load(HELLO, &["hello", "world"], &["A=B"], frame_allocator)
+        .expect("loading failed");
+    let rsp = program.stack_pointer.as_u64();
+    assert_eq!(rsp % 16, 0);
+    let mapper = program.address_space.mapper();
+
+    let words: Vec<u64> = (0..24).map(|i| read_u64(&mapper, rsp + i * 8)).collect();
+    assert_eq!(words[0], 2); //argc
+    assert_ne!(words[1], 0);
+    assert_ne!(words[2], 0);
+    assert_eq!(words[3], 0); //end of argv
+    assert_ne!(words[4], 0);
+    assert_eq!(words[5], 0); //end of envp
+
+    let auxv: Vec<(u64, u64)> = words[6..].chunks(2).map(|pair| (pair[0], pair[1])).collect();
+    assert!(auxv.contains(&(9, ENTRY))); //AT_ENTRY
+    assert!(auxv.contains(&(5, 3))); //AT_PHNUM
+    assert!(auxv.contains(&(6, 4096))); //AT_PAGESZ
+
+    //argv[1] points at "world\0", which is followed by "hello\0" further up
+    assert_eq!(read_u64(&mapper, words[2]) & 0xFFFF_FFFF_FFFF, u64::from_le_bytes(*b"world\0\0\0"));
+
+    serial_println!("[ok]");
+}
+
+fn rejects_bad_magic(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::rejects_bad_magic...\t");
+
+    let mut corrupted = Vec::from(HELLO);
+    corrupted[1] = b'X';
+    match elf::load(&corrupted, &[], &[], frame_allocator) {
+        Err(ElfError::BadMagic) => {}
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("corrupted binary was loaded"),
+    }
+
+    serial_println!("[ok]");
+}
+
+fn frees_address_space_on_error(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::frees_address_space_on_error...\t");
+
+    //move the second PT_LOAD segment into kernel space, so loading fails after the first was mapped
+    let mut corrupted = Vec::from(HELLO);
+    let header_offset = u64::from_le_bytes(corrupted[0x20..0x28].try_into().unwrap()) as usize;
+    let header_count = u16::from_le_bytes(corrupted[0x38..0x3a].try_into().unwrap()) as usize;
+    let second_load = (0..header_count)
+        .map(|i| header_offset + i * 56)
+        .filter(|&offset| corrupted[offset..offset + 4] == [1, 0, 0, 0]) //PT_LOAD
+        .nth(1)
+        .expect("no second PT_LOAD segment");
+    corrupted[second_load + 16..second_load + 24].copy_from_slice(&0x1000u64.to_le_bytes());
+
+    //freed frames are handed out again last in, first out
+    let marker = frame_allocator.allocate_frame().expect("out of frames");
+    unsafe { frame_allocator.deallocate_frame(marker) };
+    match elf::load(&corrupted, &[], &[], frame_allocator) {
+        Err(ElfError::SegmentOutsideUserRegion) => {}
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("corrupted binary was loaded"),
+    }
+    assert_eq!(frame_allocator.allocate_frame(), Some(marker), "the level 4 table was not freed");
+
+    serial_println!("[ok]");
+}
+
+fn rejects_oversized_arguments(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::rejects_oversized_arguments...\t");
+
+    let chunk = "x".repeat(4096);
+    let args: Vec<&str> = (0..=elf::USER_STACK_SIZE / 4096).map(|_| chunk.as_str()).collect();
+    match elf::load(HELLO, &args, &[], frame_allocator) {
+        Err(ElfError::StackTooSmall) => {}
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("arguments larger than the stack were accepted"),
+    }
+
+    serial_println!("[ok]");
+}
+
+fn rejects_entry_outside_code(frame_allocator: &mut BootInfoFrameAllocator) {
+    serial_print!("elf_loader::rejects_entry_outside_code...\t");
+
+    let mut corrupted = Vec::from(HELLO);
+    corrupted[24..32].copy_from_slice(&DATA.to_le_bytes()); //e_entry, into the data segment
+    match elf::load(&corrupted, &[], &[], frame_allocator) {
+        Err(ElfError::EntryNotExecutable) => {}
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("binary with a bad entry point was loaded"),
+    }
+
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,80 @@
+//run using 'cargo test --test executor_metrics'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use rust_os::task::executor::Executor;
+use rust_os::task::metrics::Monitor;
+use rust_os::task::{timer, Task};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static MONITOR: OnceCell<Monitor> = OnceCell::uninit();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("executor_metrics::snapshot...\t");
+    let mut executor = Executor::new();
+    executor.set_slow_poll_threshold(Duration::from_millis(1));
+    MONITOR.try_init_once(|| executor.monitor()).expect("monitor already set");
+    executor.spawn_task(Task::new(sleeper()).with_name("sleeper"));
+    executor.spawn_task(Task::new(blocker()).with_name("blocker"));
+    executor.spawn_task(Task::new(check()).with_name("check"));
+    executor.run();
+}
+
+async fn sleeper() {
+    timer::sleep(Duration::from_millis(20)).await;
+    timer::sleep(Duration::from_secs(3600)).await;
+}
+
+//busy waits inside poll, which the executor should report as a slow poll
+async fn blocker() {
+    rust_os::time::busy_wait(Duration::from_millis(5));
+    timer::sleep(Duration::from_secs(3600)).await;
+}
+
+async fn check() {
+    timer::sleep(Duration::from_millis(200)).await; //a few timer ticks, the sleeper is done by then
+    let tasks = MONITOR.get().unwrap().snapshot();
+
+    let sleeper = tasks.iter().find(|task| task.name == "sleeper").expect("sleeper missing");
+    assert_eq!(sleeper.polls, 2); //first poll, then once after its timer fired
+    assert!(sleeper.last_wake.is_some());
+    assert_eq!(sleeper.slow_polls, 0);
+
+    let blocker = tasks.iter().find(|task| task.name == "blocker").expect("blocker missing");
+    assert_eq!(blocker.polls, 1);
+    assert_eq!(blocker.slow_polls, 1);
+    assert!(blocker.longest_poll >= Duration::from_millis(5));
+    assert!(blocker.busy_time >= blocker.longest_poll);
+    assert!(blocker.spawned_at <= sleeper.spawned_at + Duration::from_millis(1));
+
+    let check = tasks.iter().find(|task| task.name == "check").expect("check missing");
+    assert_eq!(check.polls, 1); //the poll that is running right now is not counted yet
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
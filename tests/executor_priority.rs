@@ -0,0 +1,78 @@
+//run using 'cargo test --test executor_priority'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use rust_os::task::{executor::{Executor, MAX_POLLS_PER_ROUND}, Priority};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use spin::Mutex;
+
+static ORDER: Mutex<Vec<Priority>> = Mutex::new(Vec::new());
+static HOG_POLLS: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("executor_priority::higher_priority_first_without_starvation...\t");
+    let mut executor = Executor::new();
+    //spawned in reverse order of their priority
+    executor.spawn_with_priority(check(), Priority::Low);
+    executor.spawn_with_priority(record(Priority::Normal), Priority::Normal);
+    executor.spawn_with_priority(record(Priority::High), Priority::High);
+    executor.spawn_with_priority(hog(), Priority::High);
+    executor.run();
+}
+
+async fn record(priority: Priority) {
+    ORDER.lock().push(priority);
+}
+
+//wakes itself on every poll and never finishes, so the executor is never idle
+struct Hog;
+
+impl Future for Hog {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        HOG_POLLS.fetch_add(1, Ordering::Relaxed);
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+async fn hog() {
+    Hog.await
+}
+
+//only gets to run if the hog is held back by the per round poll limit
+async fn check() {
+    assert_eq!(*ORDER.lock(), [Priority::High, Priority::Normal]);
+    assert_eq!(HOG_POLLS.load(Ordering::Relaxed), MAX_POLLS_PER_ROUND as u64);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,96 @@
+//run using 'cargo test --test executor_stress'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use rust_os::task::executor::{Executor, Spawner};
+use rust_os::task::Task;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+//far beyond the 100 entries the queue used to be limited to, but spawned in batches to fit the heap
+const TOTAL_TASKS: u64 = 4000;
+const BATCH: u64 = 200;
+
+static FINISHED: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("executor_stress::thousands_of_tasks...\t");
+    let mut executor = Executor::new();
+    for _ in 0..BATCH {
+        executor.spawn_task(Task::new(worker()));
+    }
+    let spawner = executor.spawner();
+    executor.spawn_task(Task::new(driver(spawner)));
+    executor.run();
+}
+
+//wakes itself several times before returning Pending once, so a single wakeup must be queued only once
+struct WakeRepeatedly {
+    polled: bool,
+}
+
+impl Future for WakeRepeatedly {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.polled {
+            return Poll::Ready(());
+        }
+        self.polled = true;
+        for _ in 0..3 {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+fn yield_now() -> WakeRepeatedly {
+    WakeRepeatedly { polled: false }
+}
+
+async fn worker() {
+    yield_now().await;
+    FINISHED.fetch_add(1, Ordering::Relaxed);
+}
+
+//spawns the next batch once the previous one ran, until all of them did
+async fn driver(spawner: Spawner) {
+    let mut spawned = BATCH;
+    while FINISHED.load(Ordering::Relaxed) < TOTAL_TASKS {
+        if spawned < TOTAL_TASKS && FINISHED.load(Ordering::Relaxed) == spawned {
+            for _ in 0..BATCH.min(TOTAL_TASKS - spawned) {
+                spawner.spawn(worker()).detach();
+            }
+            spawned += BATCH.min(TOTAL_TASKS - spawned);
+        }
+        yield_now().await;
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,103 @@
+//run using 'cargo test --test hpet'
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use rust_os::hpet::{self, HpetClock};
+use rust_os::task::timer;
+use rust_os::time::{ClockSource, Instant};
+
+//monotonic clock readings right before and after `hpet::init` recalibrated the TSC
+static BEFORE_INIT: AtomicU64 = AtomicU64::new(0);
+static AFTER_INIT: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    BEFORE_INIT.store(Instant::now().since_boot().as_nanos() as u64, Ordering::SeqCst);
+    hpet::init(&mut mapper, &mut frame_allocator).expect("no HPET, QEMU provides one by default");
+    AFTER_INIT.store(Instant::now().since_boot().as_nanos() as u64, Ordering::SeqCst);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn counter_runs() {
+    assert!(hpet::is_present());
+    assert!(hpet::frequency() >= 10_000_000); //the specification asks for at least 10 MHz
+    let start = HpetClock.nanos();
+    rust_os::time::busy_wait(Duration::from_millis(2));
+    let elapsed = HpetClock.nanos() - start;
+    //both clocks agree roughly, now that the TSC was calibrated against the HPET
+    assert!(elapsed >= 1_500_000 && elapsed < 20_000_000, "elapsed {} ns", elapsed);
+}
+
+#[test_case]
+fn recalibration_keeps_time_monotonic() {
+    assert!(AFTER_INIT.load(Ordering::SeqCst) >= BEFORE_INIT.load(Ordering::SeqCst));
+}
+
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+//with a tick of 100 ms, a short sleep can only end early enough if the one-shot timer woke it
+#[test_case]
+fn one_shot_wakes_sleep_between_ticks() {
+    hpet::enable_one_shot_interrupts(10).expect("HPET without legacy routing");
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let start = Instant::now();
+    let mut sleep = pin!(timer::sleep(Duration::from_millis(3)));
+    while sleep.as_mut().poll(&mut context).is_pending() {
+        use x86_64::instructions::interrupts;
+        //check with interrupts off, so the wakeup cannot slip in between the check and the hlt
+        interrupts::disable();
+        while !flag.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+        interrupts::enable();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(3));
+    assert!(elapsed < Duration::from_millis(50), "woke after {:?}", elapsed);
+}
@@ -0,0 +1,133 @@
+//run using 'cargo test --test join_handle'
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use rust_os::task::{simple_executor::SimpleExecutor, sync::Notify, JoinError, Task};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+//never finishes, but keeps its waker like any real pending future, which the test executor checks
+async fn forever<T>() -> T {
+    let notify = Notify::new();
+    notify.notified().await;
+    unreachable!("nobody can notify it")
+}
+
+#[test_case]
+fn handle_resolves_to_output() {
+    let result = Rc::new(Cell::new(None));
+    let mut executor = SimpleExecutor::new();
+
+    let (task, handle) = Task::with_join_handle(async { 6 * 7 });
+    let waiter_result = result.clone();
+    //if the waiter is polled first, it has to wait for the other task
+    executor.spawn(Task::new(async move { waiter_result.set(Some(handle.await)) }));
+    executor.spawn(task);
+    executor.run();
+
+    assert_eq!(result.get(), Some(Ok(42)));
+}
+
+#[test_case]
+fn detached_task_still_runs() {
+    let ran = Rc::new(Cell::new(false));
+    let mut executor = SimpleExecutor::new();
+
+    let task_ran = ran.clone();
+    let (task, handle) = Task::with_join_handle(async move { task_ran.set(true) });
+    handle.detach();
+    executor.spawn(task);
+    executor.run();
+
+    assert!(ran.get());
+}
+
+#[test_case]
+fn is_finished_after_run() {
+    let mut executor = SimpleExecutor::new();
+
+    let (task, handle) = Task::with_join_handle(async { "done" });
+    assert!(!handle.is_finished());
+    executor.spawn(task);
+    executor.run();
+
+    assert!(handle.is_finished());
+}
+
+#[test_case]
+fn abort_resolves_joiner_with_cancelled() {
+    let result = Rc::new(Cell::new(None));
+    let mut executor = SimpleExecutor::new();
+
+    let (task, handle) = Task::with_join_handle(forever::<u32>());
+    let abort = handle.abort_handle();
+    let waiter_result = result.clone();
+    executor.spawn(task);
+    executor.spawn(Task::new(async move { waiter_result.set(Some(handle.await)) }));
+    executor.spawn(Task::new(async move { abort.abort() }));
+    executor.run(); //only returns once the pending task was removed
+
+    assert_eq!(result.get(), Some(Err(JoinError::Cancelled)));
+}
+
+//a task created while the parent is polled is its child, aborting the parent cancels it too
+#[test_case]
+fn abort_cancels_children() {
+    let child = Rc::new(RefCell::new(None));
+    let mut executor = SimpleExecutor::new();
+
+    let parent_child = child.clone();
+    let (parent, parent_handle) = Task::with_join_handle(async move {
+        *parent_child.borrow_mut() = Some(Task::with_join_handle(forever::<()>()));
+        forever::<()>().await
+    });
+    let abort = parent_handle.abort_handle();
+    executor.spawn(parent);
+    executor.run_until_stalled(); //let the parent create the child before it is aborted
+    executor.spawn(Task::new(async move { abort.abort() }));
+    executor.run();
+    assert!(parent_handle.is_finished());
+
+    //the child never ran, it notices the abort on its first poll
+    let (child_task, child_handle) = child.borrow_mut().take().expect("parent did not create the child");
+    let result = Rc::new(Cell::new(None));
+    let waiter_result = result.clone();
+    executor.spawn(child_task);
+    executor.spawn(Task::new(async move { waiter_result.set(Some(child_handle.await)) }));
+    executor.run();
+
+    assert_eq!(result.get(), Some(Err(JoinError::Cancelled)));
+}
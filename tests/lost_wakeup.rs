@@ -0,0 +1,88 @@
+//run using 'cargo test --test lost_wakeup'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use rust_os::task::{simple_executor::SimpleExecutor, Task};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+//each case has to end in the executor's panic, whose handler then starts the next case
+const CASES: &[(&str, fn())] = &[
+    ("lost_wakeup::plain_task", plain_task),
+    ("lost_wakeup::joinable_task", joinable_task),
+];
+static NEXT_CASE: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    run_next_case();
+}
+
+fn run_next_case() -> ! {
+    match CASES.get(NEXT_CASE.fetch_add(1, Ordering::SeqCst)) {
+        Some((name, case)) => {
+            serial_print!("{}...\t", name);
+            case();
+            serial_println!("[test did not panic]");
+            exit_qemu(QemuExitCode::Failed);
+        }
+        None => exit_qemu(QemuExitCode::Success),
+    }
+    loop {}
+}
+
+//returns Pending and drops the waker, the executor has to notice
+struct Forgetful;
+
+impl Future for Forgetful {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        Poll::Pending
+    }
+}
+
+fn plain_task() {
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(Forgetful).with_name("forgetful"));
+    executor.run();
+}
+
+//the join handle wrapper has a waker of its own for aborts, which must not hide the bug
+fn joinable_task() {
+    let mut executor = SimpleExecutor::new();
+    let (task, handle) = Task::with_join_handle(Forgetful);
+    handle.detach();
+    executor.spawn(task);
+    executor.run();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !info.location().map_or(false, |location| location.file().ends_with("simple_executor.rs")) {
+        rust_os::test_panic_handler(info); //some other check failed
+    }
+    serial_println!("[ok]");
+    run_next_case();
+}
@@ -0,0 +1,64 @@
+//run using 'cargo test --test nmi'
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::gdt::{self, IST_STACK_SIZE, NMI_IST_INDEX};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("nmi::nmi_on_ist_stack...\t");
+
+    gdt::init();
+    init_test_idt();
+
+    //destroy the current stack pointer before raising the NMI, so the handler can only run
+    //if the CPU switched to the dedicated NMI stack
+    unsafe {
+        asm!("mov rsp, 0", "int 2", options(noreturn));
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_nmi_handler)
+                .set_stack_index(NMI_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_nmi_handler(_stack_frame: InterruptStackFrame) {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+
+    //check that we really are on the NMI stack and not somewhere else
+    let top = gdt::ist_stack_top(NMI_IST_INDEX).as_u64();
+    if rsp <= top && rsp > top - IST_STACK_SIZE as u64 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: NMI handler ran on stack {:#x}, expected below {:#x}", rsp, top);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
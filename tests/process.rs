@@ -0,0 +1,71 @@
+//run using 'cargo test --test process'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use rust_os::interrupts::{irq::{self, IrqReturn}, InterruptIndex};
+use rust_os::process::{self, Pid, ProcessError, ProcessState, KERNEL_PID};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+//see user_programs/hello.s, it exits with the value of a .data variable plus a zeroed .bss variable
+static HELLO: &[u8] = include_bytes!("user_programs/hello.elf");
+const EXIT_STATUS: i64 = 0x1122_3344_5566_7788;
+
+static CHILD: Mutex<Option<Pid>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("process::spawn...\t");
+    let pid = process::spawn("hello", HELLO, &["hello"], KERNEL_PID, &mut frame_allocator)
+        .expect("spawn failed");
+    assert_eq!(process::state(pid), Some(ProcessState::Running));
+    assert_eq!(process::parent(pid), Some(KERNEL_PID));
+    assert_eq!(process::children(KERNEL_PID), [pid]);
+    assert!(matches!(process::try_wait(KERNEL_PID, pid), Ok(None)));
+    assert!(matches!(process::try_wait(pid, pid), Err(ProcessError::NotAChild)));
+    serial_println!("[ok]");
+
+    serial_print!("process::exit_and_reap...\t");
+    *CHILD.lock() = Some(pid);
+    irq::register(InterruptIndex::Timer.irq(), check_exited).expect("failed to register timer handler");
+    process::run(pid);
+}
+
+//the exiting process leaves the CPU halting with interrupts on, so look for the zombie on every tick
+fn check_exited(_irq: u8) -> IrqReturn {
+    let pid = CHILD.lock().expect("no child to wait for");
+    match process::try_wait(KERNEL_PID, pid) {
+        Ok(Some(status)) => {
+            assert_eq!(status, EXIT_STATUS);
+            assert_eq!(process::state(pid), None);
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        Ok(None) => {}
+        Err(err) => panic!("wait failed: {:?}", err),
+    }
+    IrqReturn::NotHandled
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
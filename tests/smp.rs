@@ -0,0 +1,47 @@
+//run using 'cargo test --test smp'
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{acpi, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+//every processor the firmware reports as usable should have come up
+#[test_case]
+fn all_cpus_online() {
+    let madt = acpi::madt().expect("no MADT");
+    let enabled = madt.local_apics.iter().filter(|lapic| lapic.enabled).count();
+    assert!(enabled > 1, "QEMU was not started with -smp");
+    assert_eq!(smp::cpus_online(), enabled);
+}
@@ -0,0 +1,68 @@
+//run using 'cargo test --test spawner'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rust_os::interrupts::{irq::{self, IrqReturn}, InterruptIndex};
+use rust_os::task::executor::{Executor, Spawner};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+static SPAWNED_FROM_IRQ: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    SPAWNER.try_init_once(|| executor.spawner()).expect("spawner already set");
+    executor.spawn(run_tests());
+    executor.run();
+}
+
+//the executor never returns, so the last check ends the test from inside a task
+async fn run_tests() {
+    serial_print!("spawner::spawn_from_task...\t");
+    let spawner = SPAWNER.get().unwrap().clone();
+    let handle = spawner.spawn(async { 6 * 7 });
+    assert_eq!(handle.await, Ok(42));
+    serial_println!("[ok]");
+
+    serial_print!("spawner::spawn_from_interrupt...\t");
+    irq::register(InterruptIndex::Timer.irq(), spawn_from_timer).expect("failed to register timer handler");
+}
+
+fn spawn_from_timer(_irq: u8) -> IrqReturn {
+    if !SPAWNED_FROM_IRQ.swap(true, Ordering::SeqCst) {
+        let spawner = SPAWNER.get().expect("spawner not set");
+        spawner
+            .spawn(async {
+                serial_println!("[ok]");
+                exit_qemu(QemuExitCode::Success);
+            })
+            .detach();
+    }
+    IrqReturn::NotHandled
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
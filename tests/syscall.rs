@@ -0,0 +1,61 @@
+//run using 'cargo test --test syscall'
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use rust_os::interrupts::{irq::{self, IrqReturn}, InterruptIndex};
+use rust_os::process::{self, Pid, KERNEL_PID};
+use rust_os::syscall::SyscallError;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+//see user_programs/bad_pointer.s, it exits with the sum of what its two bad writes returned
+static BAD_POINTER: &[u8] = include_bytes!("user_programs/bad_pointer.elf");
+
+static CHILD: Mutex<Option<Pid>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("syscall::rejects_bad_arguments...\t");
+    let pid = process::spawn("bad_pointer", BAD_POINTER, &["bad_pointer"], KERNEL_PID, &mut frame_allocator)
+        .expect("spawn failed");
+    *CHILD.lock() = Some(pid);
+    irq::register(InterruptIndex::Timer.irq(), check_exited).expect("failed to register timer handler");
+    process::run(pid); //would hang on a kernel page fault or panic if the arguments were not checked
+}
+
+fn check_exited(_irq: u8) -> IrqReturn {
+    let pid = CHILD.lock().expect("no child to wait for");
+    match process::try_wait(KERNEL_PID, pid) {
+        Ok(Some(status)) => {
+            assert_eq!(status, 2 * SyscallError::BadAddress as i64 + SyscallError::InvalidArgument as i64);
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        Ok(None) => {}
+        Err(err) => panic!("wait failed: {:?}", err),
+    }
+    IrqReturn::NotHandled
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
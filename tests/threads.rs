@@ -0,0 +1,111 @@
+//run using 'cargo test --test threads'
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rust_os::memory::BootInfoFrameAllocator;
+use rust_os::thread::{self, ThreadId};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+
+//what `thread::spawn` needs to map stacks, handed over by `main`
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> ThreadId {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory not handed over");
+    thread::spawn(name, f, mapper, frame_allocator).expect("failed to map thread stack")
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn yield_runs_other_thread() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    spawn("flag", || RAN.store(true, Ordering::SeqCst));
+    for _ in 0..100 {
+        if RAN.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::yield_now();
+    }
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+//neither side ever yields, so both only make progress if the timer preempts them
+#[test_case]
+fn busy_threads_are_preempted() {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static STOPPED: AtomicBool = AtomicBool::new(false);
+
+    spawn("spinner", || {
+        while !STOP.load(Ordering::Relaxed) {
+            COUNTER.fetch_add(1, Ordering::Relaxed);
+        }
+        STOPPED.store(true, Ordering::SeqCst);
+    });
+    while COUNTER.load(Ordering::Relaxed) < 1_000_000 {
+        core::hint::spin_loop();
+    }
+    //don't leave the spinner (and its stack) around for the next test
+    STOP.store(true, Ordering::Relaxed);
+    while !STOPPED.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn finished_threads_leave_the_queue() {
+    static DONE: AtomicU64 = AtomicU64::new(0);
+
+    let before = thread::ready_count();
+    for _ in 0..4 {
+        spawn("short", || {
+            DONE.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    while DONE.load(Ordering::SeqCst) < 4 {
+        thread::yield_now();
+    }
+    //a thread may have been preempted between counting and returning, give it a chance to finish
+    for _ in 0..100 {
+        if thread::ready_count() == before {
+            break;
+        }
+        thread::yield_now();
+    }
+    assert_eq!(thread::ready_count(), before);
+}
@@ -0,0 +1,76 @@
+//run using 'cargo test --test user_mode'
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::usermode::{self, USER_REGION_START};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+//cli is privileged, so it faults in ring 3; the jmp to itself is never reached
+const USER_CODE: [u8; 3] = [0xFA, 0xEB, 0xFE];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+
+    serial_print!("user_mode::privileged_instruction_faults...\t");
+
+    rust_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    let code = VirtAddr::new(USER_REGION_START);
+    let stack = VirtAddr::new(USER_REGION_START + 0x10000);
+    memory::map_user_pages(code, 4096, PageTableFlags::WRITABLE, &mut mapper, &mut frame_allocator)
+        .expect("mapping user code failed");
+    memory::map_user_pages(stack, 4096, PageTableFlags::WRITABLE, &mut mapper, &mut frame_allocator)
+        .expect("mapping user stack failed");
+    unsafe {
+        code.as_mut_ptr::<[u8; 3]>().write(USER_CODE);
+        usermode::enter_user_mode(code, stack + 4096u64);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.general_protection_fault.set_handler_fn(test_gp_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+//reaching this from ring 3 means the CPU found the TSS privilege stack and switched back to the kernel
+extern "x86-interrupt" fn test_gp_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    if usermode::from_user_mode(stack_frame.code_segment)
+        && stack_frame.instruction_pointer.as_u64() == USER_REGION_START
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected fault\n{:#?}", stack_frame);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,36 @@
+# user program for tests/syscall.rs: hands write() pointers and sleep() a time it must not get away with
+# build: as bad_pointer.s -o bad_pointer.o && ld -static -nostdlib -z max-page-size=0x1000 -Ttext=0x200000401000 -e _start bad_pointer.o -o bad_pointer.elf && strip bad_pointer.elf
+    .set SYS_WRITE, 0
+    .set SYS_SLEEP, 2
+    .set SYS_EXIT, 3
+
+    .text
+    .global _start
+_start:
+    mov $SYS_WRITE, %eax
+    movabs $0x200000800000, %rdi    # inside the user region, but nothing is mapped there
+    mov $16, %esi
+    syscall
+    mov %rax, %r12
+    mov $SYS_WRITE, %eax
+    lea message(%rip), %rdi         # starts on the mapped text page, runs into the unmapped one after it
+    mov $0x1000, %esi
+    syscall
+    mov %rax, %r13
+    mov $SYS_SLEEP, %eax
+    mov $-1, %rdi                   # overflows the deadline
+    syscall
+    mov %rax, %r14
+    mov $SYS_SLEEP, %eax
+    mov $5, %edi                    # a real sleep, returns 0
+    syscall
+    add %rax, %r14
+    mov $SYS_EXIT, %eax
+    mov %r12, %rdi
+    add %r13, %rdi
+    add %r14, %rdi                  # -14 + -14 + -22 if all were refused
+    syscall
+1:  jmp 1b
+
+message:
+    .ascii "never printed\n"
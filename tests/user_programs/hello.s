@@ -0,0 +1,29 @@
+# sample user program for tests/elf_loader.rs
+# build: as hello.s -o hello.o && ld -static -nostdlib -z max-page-size=0x1000 -Ttext=0x200000401000 -Tdata=0x200000600000 -e _start hello.o -o hello.elf && strip hello.elf
+    .set SYS_WRITE, 0
+    .set SYS_EXIT, 3
+
+    .text
+    .global _start
+_start:
+    mov $SYS_WRITE, %eax
+    lea message(%rip), %rdi
+    mov $(message_end - message), %esi
+    syscall
+    mov $SYS_EXIT, %eax
+    mov counter(%rip), %rdi     # 0x1122334455667788 from .data
+    add zeroed(%rip), %rdi      # .bss must read as zero
+    syscall
+1:  jmp 1b
+
+message:
+    .ascii "Hello from user space!\n"
+message_end:
+
+    .data
+counter:
+    .quad 0x1122334455667788
+
+    .bss
+zeroed:
+    .skip 0x2000